@@ -0,0 +1,47 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Writes the versions of all migrations into `$OUT_DIR/migration_versions.rs`, so the server can
+// tell a database migrated by a newer release from one it can migrate itself.
+// Also sets `GIT_HASH` for `/version`, taken from the environment where builds have no checkout.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    let git_hash = std::env::var("GIT_HASH")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            String::from_utf8(output.stdout).ok()
+        })
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("Failed to read migrations directory")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // Same as diesel: the part before the first `_`, without dashes
+            name.split('_').next().map(|v| v.replace('-', ""))
+        })
+        .filter(|version| !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()))
+        .collect();
+    versions.sort();
+    let out = Path::new(&std::env::var("OUT_DIR").unwrap()).join("migration_versions.rs");
+    fs::write(
+        out,
+        format!("pub const MIGRATION_VERSIONS: &[&str] = &{:?};\n", versions),
+    )
+    .expect("Failed to write migration versions");
+}
@@ -0,0 +1,14 @@
+CREATE TABLE refresh_token (
+  id SERIAL NOT NULL PRIMARY KEY,
+  token_hash TEXT NOT NULL UNIQUE,
+  family TEXT NOT NULL,
+  user_id_fk INTEGER NOT NULL,
+  used BOOLEAN NOT NULL DEFAULT FALSE,
+  revoked BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  CONSTRAINT fk_user
+      FOREIGN KEY(user_id_fk)
+	  REFERENCES users(id)
+);
+CREATE INDEX refresh_token_family_idx ON refresh_token (family);
@@ -0,0 +1,19 @@
+CREATE TABLE totp_secret (
+  user_id_fk INTEGER NOT NULL PRIMARY KEY,
+  secret TEXT NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT FALSE,
+  last_used_step BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL,
+  CONSTRAINT fk_user
+      FOREIGN KEY(user_id_fk)
+	  REFERENCES users(id)
+);
+CREATE TABLE recovery_code (
+  id SERIAL NOT NULL PRIMARY KEY,
+  user_id_fk INTEGER NOT NULL,
+  code_hash TEXT NOT NULL,
+  used BOOLEAN NOT NULL DEFAULT FALSE,
+  CONSTRAINT fk_user
+      FOREIGN KEY(user_id_fk)
+	  REFERENCES users(id)
+);
@@ -0,0 +1,2 @@
+DROP TABLE account_lockout;
+DROP TABLE rate_limit_bucket;
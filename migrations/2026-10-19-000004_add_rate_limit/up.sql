@@ -0,0 +1,13 @@
+CREATE TABLE rate_limit_bucket (
+  bucket_key TEXT NOT NULL PRIMARY KEY,
+  tokens DOUBLE PRECISION NOT NULL,
+  updated_at TIMESTAMP NOT NULL
+);
+CREATE TABLE account_lockout (
+  user_id_fk INTEGER NOT NULL PRIMARY KEY,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  locked_until TIMESTAMP,
+  CONSTRAINT fk_user
+      FOREIGN KEY(user_id_fk)
+	  REFERENCES users(id)
+);
@@ -0,0 +1 @@
+ALTER TABLE users ADD deleted_at TIMESTAMP;
@@ -0,0 +1 @@
+ALTER TABLE verification_token DROP COLUMN new_email;
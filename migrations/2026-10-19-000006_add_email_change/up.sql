@@ -0,0 +1 @@
+ALTER TABLE verification_token ADD new_email TEXT;
@@ -0,0 +1,21 @@
+-- Accounts whose emails only differ in case or surrounding whitespace would collide on the index.
+-- Which of them to keep is for an operator to decide, so stop with a list of them instead.
+DO $$
+DECLARE
+  duplicates TEXT;
+BEGIN
+  SELECT string_agg(format('%s (user ids %s)', normalized, ids), '; ')
+    INTO duplicates
+    FROM (
+      SELECT lower(trim(email)) AS normalized, string_agg(id::TEXT, ', ' ORDER BY id) AS ids
+        FROM users
+        GROUP BY lower(trim(email))
+        HAVING count(*) > 1
+    ) AS duplicate;
+  IF duplicates IS NOT NULL THEN
+    RAISE EXCEPTION 'These emails belong to more than one account once lowercased: %. Merge or delete the extra accounts, then migrate again.', duplicates;
+  END IF;
+END
+$$;
+UPDATE users SET email = lower(trim(email));
+CREATE UNIQUE INDEX users_email_lower_idx ON users (lower(email));
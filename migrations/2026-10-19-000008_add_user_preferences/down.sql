@@ -0,0 +1,5 @@
+ALTER TABLE users
+  DROP COLUMN display_name,
+  DROP COLUMN default_trove,
+  DROP COLUMN timezone,
+  DROP COLUMN sync_conflict_strategy;
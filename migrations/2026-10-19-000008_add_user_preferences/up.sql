@@ -0,0 +1,5 @@
+ALTER TABLE users
+  ADD display_name TEXT,
+  ADD default_trove INTEGER REFERENCES trove(id) ON DELETE SET NULL,
+  ADD timezone TEXT NOT NULL DEFAULT 'UTC',
+  ADD sync_conflict_strategy TEXT NOT NULL DEFAULT 'ask';
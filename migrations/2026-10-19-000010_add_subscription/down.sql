@@ -0,0 +1,2 @@
+DROP TABLE payment_event;
+ALTER TABLE users DROP COLUMN plan;
@@ -0,0 +1,13 @@
+ALTER TABLE users ADD plan TEXT;
+
+CREATE TABLE payment_event (
+  id SERIAL NOT NULL PRIMARY KEY,
+  event_id TEXT NOT NULL UNIQUE,
+  kind TEXT NOT NULL,
+  user_id_fk INTEGER NOT NULL,
+  plan TEXT,
+  created_at TIMESTAMP NOT NULL,
+  CONSTRAINT fk_user
+      FOREIGN KEY(user_id_fk)
+	  REFERENCES users(id)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE audit_event (
+  id SERIAL NOT NULL PRIMARY KEY,
+  user_id_fk INTEGER REFERENCES users(id),
+  actor_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  action TEXT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  details JSONB,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX audit_event_user_idx ON audit_event (user_id_fk, created_at);
@@ -0,0 +1,20 @@
+-- One row per periodic job, claimed by the instance that runs it next
+CREATE TABLE job (
+  name TEXT NOT NULL PRIMARY KEY,
+  next_run_at TIMESTAMP NOT NULL,
+  locked_by TEXT,
+  locked_until TIMESTAMP
+);
+
+CREATE TABLE job_run (
+  id SERIAL NOT NULL PRIMARY KEY,
+  job TEXT NOT NULL,
+  instance TEXT NOT NULL,
+  started_at TIMESTAMP NOT NULL,
+  finished_at TIMESTAMP,
+  success BOOLEAN,
+  affected BIGINT,
+  error TEXT
+);
+
+CREATE INDEX job_run_job_idx ON job_run (job, started_at);
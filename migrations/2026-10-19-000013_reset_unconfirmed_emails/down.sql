@@ -0,0 +1,2 @@
+-- Which flags were reset isn't recorded
+SELECT 1;
@@ -0,0 +1,9 @@
+-- `verified` used to be a copy of VERIFY_USER at registration. Only addresses whose link was
+-- opened stay verified.
+UPDATE users SET verified = FALSE
+WHERE verified AND NOT EXISTS (
+    SELECT 1 FROM verification_token t
+    WHERE t.user_id_fk = users.id
+      AND t.used
+      AND t.purpose IN ('verify_email', 'change_email')
+);
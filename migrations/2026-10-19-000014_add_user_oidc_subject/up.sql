@@ -0,0 +1 @@
+ALTER TABLE users ADD oidc_subject TEXT UNIQUE;
@@ -0,0 +1 @@
+ALTER TABLE users ADD canceled_at TIMESTAMP;
@@ -0,0 +1,327 @@
+use crate::audit::{self, Event, Origin};
+use crate::auth::authenticated_user;
+use crate::errors::ServiceError;
+use crate::handlers::{db_get_user_by_id, revoke_user_tokens, usage_for, Usage};
+use crate::logging;
+use crate::models::{FlagsChange, Trove, User, UserProfile};
+use crate::quota;
+use crate::schema;
+use crate::utils::decode_text;
+use crate::Pool;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct UserSearch {
+    // Part of the email to look for
+    pub q: Option<String>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserList {
+    pub total: i64,
+    pub users: Vec<UserProfile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDetail {
+    pub user: UserProfile,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InputUserFlags {
+    pub verified: Option<bool>,
+    pub subscribed: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub users: i64,
+    pub verified: i64,
+    pub subscribed: i64,
+    pub admins: i64,
+    pub disabled: i64,
+    pub pending_deletion: i64,
+    pub troves: i64,
+    pub active_api_tokens: i64,
+}
+
+// What admins get to see of a trove revision, its content stays private
+#[derive(Debug, Serialize)]
+pub struct TroveMetadata {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub bytes: usize,
+    pub commands: Option<usize>,
+}
+
+// Handler for GET /admin/users
+pub async fn list_users(
+    db: web::Data<Pool>,
+    search: web::Query<UserSearch>,
+) -> Result<HttpResponse, Error> {
+    let search = search.into_inner();
+    Ok(logging::db_block("db_search_users", move || db_search_users(db, search))
+        .await
+        .map(|list| HttpResponse::Ok().json(list))
+        .map_err(ServiceError::from)?)
+}
+
+// Handler for GET /admin/users/{id}
+pub async fn get_user(db: web::Data<Pool>, user_id: web::Path<i32>) -> Result<HttpResponse, Error> {
+    let user_id = user_id.into_inner();
+    let db_clone = db.clone();
+    let user = logging::db_block("db_get_user_by_id", move || db_get_user_by_id(db_clone, user_id))
+        .await
+        .map_err(ServiceError::from)?;
+    let usage = usage_for(db, &user).await?;
+    Ok(HttpResponse::Ok().json(UserDetail {
+        user: UserProfile::from(&user),
+        usage,
+    }))
+}
+
+// Handler for PATCH /admin/users/{id}
+pub async fn update_user_flags(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    user_id: web::Path<i32>,
+    item: web::Json<InputUserFlags>,
+) -> Result<HttpResponse, Error> {
+    let admin = authenticated_user(db.clone(), auth).await?;
+    let user_id = user_id.into_inner();
+    let (verified, subscribed) = (item.verified, item.subscribed);
+    let db_clone = db.clone();
+    let user = logging::db_block("db_set_flags", move || {
+        db_set_flags(db_clone, user_id, verified, subscribed)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let event = Event::by_admin(audit::ADMIN_UPDATE_USER, admin.id, user_id)
+        .with_details(serde_json::json!({ "verified": verified, "subscribed": subscribed }));
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Ok().json(UserProfile::from(&user)))
+}
+
+// Handler for POST /admin/users/{id}/disable
+pub async fn disable_user(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    user_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let admin = authenticated_user(db.clone(), auth).await?;
+    let user_id = user_id.into_inner();
+    if admin.id == user_id {
+        return Err(ServiceError::Conflict(String::from("You can't disable your own account")).into());
+    }
+    let db_clone = db.clone();
+    let user = logging::db_block("db_set_disabled", move || {
+        db_set_disabled(db_clone, user_id, true)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let event = Event::by_admin(audit::ADMIN_DISABLE_USER, admin.id, user_id);
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Ok().json(UserProfile::from(&user)))
+}
+
+// Handler for POST /admin/users/{id}/enable
+pub async fn enable_user(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    user_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let admin = authenticated_user(db.clone(), auth).await?;
+    let user_id = user_id.into_inner();
+    let db_clone = db.clone();
+    let user = logging::db_block("db_set_disabled", move || {
+        db_set_disabled(db_clone, user_id, false)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let event = Event::by_admin(audit::ADMIN_ENABLE_USER, admin.id, user_id);
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Ok().json(UserProfile::from(&user)))
+}
+
+// Handler for POST /admin/users/{id}/revoke
+pub async fn revoke_tokens(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    user_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let admin = authenticated_user(db.clone(), auth).await?;
+    let user_id = user_id.into_inner();
+    let db_clone = db.clone();
+    logging::db_block("db_revoke_tokens", move || db_revoke_tokens(db_clone, user_id))
+        .await
+        .map_err(ServiceError::from)?;
+    let event = Event::by_admin(audit::ADMIN_REVOKE_TOKENS, admin.id, user_id);
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Ok().json("Revoked all tokens"))
+}
+
+// Handler for GET /admin/users/{id}/troves
+pub async fn list_troves(
+    db: web::Data<Pool>,
+    user_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let user_id = user_id.into_inner();
+    let troves = logging::db_block("db_get_troves", move || db_get_troves(db, user_id))
+        .await
+        .map_err(ServiceError::from)?;
+    let mut metadata = Vec::with_capacity(troves.len());
+    for t in troves {
+        let text = decode_text(t.trove_text)?;
+        metadata.push(TroveMetadata {
+            id: t.id,
+            created_at: t.created_at,
+            bytes: text.len(),
+            commands: quota::count_commands(&text),
+        });
+    }
+    Ok(HttpResponse::Ok().json(metadata))
+}
+
+// Handler for GET /admin/stats
+pub async fn get_stats(db: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    Ok(logging::db_block("db_get_stats", move || db_get_stats(db))
+        .await
+        .map(|stats| HttpResponse::Ok().json(stats))
+        .map_err(ServiceError::from)?)
+}
+
+fn db_search_users(pool: web::Data<Pool>, search: UserSearch) -> Result<UserList, ServiceError> {
+    use schema::users::dsl as us;
+    let conn = pool.get()?;
+    let pattern = format!(
+        "%{}%",
+        search
+            .q
+            .unwrap_or_default()
+            .to_lowercase()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+    let limit = search
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE);
+    let total: i64 = us::users
+        .filter(us::email.like(&pattern))
+        .count()
+        .get_result(&conn)?;
+    let found: Vec<User> = us::users
+        .filter(us::email.like(&pattern))
+        .order_by(us::id)
+        .offset(search.offset.unwrap_or(0).max(0))
+        .limit(limit)
+        .load(&conn)?;
+    Ok(UserList {
+        total,
+        users: found.iter().map(UserProfile::from).collect(),
+    })
+}
+
+fn db_set_flags(
+    pool: web::Data<Pool>,
+    user_id: i32,
+    verified: Option<bool>,
+    subscribed: Option<bool>,
+) -> Result<User, ServiceError> {
+    use schema::users::dsl as us;
+    let conn = pool.get()?;
+    let change = FlagsChange::new(verified, subscribed);
+    if change.is_empty() {
+        return Ok(us::users.find(user_id).get_result(&conn)?);
+    }
+    Ok(diesel::update(us::users.find(user_id))
+        .set(&change)
+        .get_result(&conn)?)
+}
+
+fn db_set_disabled(pool: web::Data<Pool>, user_id: i32, disabled: bool) -> Result<User, ServiceError> {
+    use schema::users::dsl as us;
+    let conn = pool.get()?;
+    Ok(diesel::update(us::users.find(user_id))
+        .set(us::disabled.eq(disabled))
+        .get_result(&conn)?)
+}
+
+fn db_revoke_tokens(pool: web::Data<Pool>, user_id: i32) -> Result<(), ServiceError> {
+    // Not found instead of silently revoking nothing
+    db_get_user_by_id(pool.clone(), user_id)?;
+    let conn = pool.get()?;
+    Ok(conn.transaction(|| revoke_user_tokens(&conn, user_id, None))?)
+}
+
+fn db_get_troves(pool: web::Data<Pool>, user_id: i32) -> Result<Vec<Trove>, ServiceError> {
+    use schema::trove::dsl as tr;
+    let conn = pool.get()?;
+    Ok(tr::trove
+        .filter(tr::user_id_fk.eq(user_id))
+        .order_by(tr::id)
+        .load(&conn)?)
+}
+
+pub fn db_get_stats(pool: web::Data<Pool>) -> Result<Stats, ServiceError> {
+    use schema::api_token::dsl as at;
+    use schema::trove::dsl as tr;
+    use schema::users::dsl as us;
+    let conn = pool.get()?;
+    Ok(Stats {
+        users: us::users.count().get_result(&conn)?,
+        verified: us::users.filter(us::verified.eq(true)).count().get_result(&conn)?,
+        subscribed: us::users.filter(us::subscribed.eq(true)).count().get_result(&conn)?,
+        admins: us::users.filter(us::admin.eq(true)).count().get_result(&conn)?,
+        disabled: us::users.filter(us::disabled.eq(true)).count().get_result(&conn)?,
+        pending_deletion: us::users
+            .filter(us::deleted_at.is_not_null())
+            .count()
+            .get_result(&conn)?,
+        troves: tr::trove.count().get_result(&conn)?,
+        active_api_tokens: at::api_token
+            .filter(at::revoked.eq(false))
+            .count()
+            .get_result(&conn)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update_sql(change: FlagsChange) -> String {
+        use schema::users::dsl as us;
+        let query = diesel::update(us::users.find(1)).set(&change);
+        diesel::debug_query::<diesel::pg::Pg, _>(&query).to_string()
+    }
+
+    #[test]
+    fn setting_subscribed_drops_plan_and_cancellation() {
+        // An old plan would make the grant lapse with the payment it belonged to
+        assert_eq!(
+            update_sql(FlagsChange::new(None, Some(true))),
+            r#"UPDATE "users" SET "subscribed" = $1, "plan" = $2, "canceled_at" = $3 WHERE "users"."id" = $4 -- binds: [true, None, None, 1]"#
+        );
+        assert_eq!(
+            update_sql(FlagsChange::new(Some(true), None)),
+            r#"UPDATE "users" SET "verified" = $1 WHERE "users"."id" = $2 -- binds: [true, 1]"#
+        );
+        assert!(FlagsChange::new(None, None).is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::auth::authenticated_user;
+use crate::errors::ServiceError;
+use crate::logging;
+use crate::models::{AuditEvent, NewAuditEvent};
+use crate::schema;
+use crate::utils::client_ip;
+use crate::Pool;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use diesel::prelude::*;
+use serde::Deserialize;
+
+pub const REGISTER: &str = "register";
+pub const LOGIN: &str = "login";
+pub const LOGIN_FAILED: &str = "login_failed";
+pub const LOGOUT: &str = "logout";
+pub const TOKEN_CREATE: &str = "token_create";
+pub const TOKEN_REVOKE: &str = "token_revoke";
+pub const TROVE_WRITE: &str = "trove_write";
+pub const PASSWORD_CHANGE: &str = "password_change";
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const EMAIL_CHANGE: &str = "email_change";
+pub const REFRESH_TOKEN_REUSE: &str = "refresh_token_reuse";
+pub const TOTP_ENABLE: &str = "totp_enable";
+pub const TOTP_DISABLE: &str = "totp_disable";
+pub const ACCOUNT_DELETE: &str = "account_delete";
+pub const ACCOUNT_RESTORE: &str = "account_restore";
+pub const ADMIN_UPDATE_USER: &str = "admin_update_user";
+pub const ADMIN_DISABLE_USER: &str = "admin_disable_user";
+pub const ADMIN_ENABLE_USER: &str = "admin_enable_user";
+pub const ADMIN_REVOKE_TOKENS: &str = "admin_revoke_tokens";
+pub const ADMIN_CREATE_USER: &str = "admin_create_user";
+pub const ADMIN_RESET_PASSWORD: &str = "admin_reset_password";
+pub const ADMIN_IMPORT: &str = "admin_import";
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+const MAX_USER_AGENT_LEN: usize = 512;
+
+// Where a request came from
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Origin {
+    pub fn of(req: &HttpRequest) -> Origin {
+        Origin {
+            ip: Some(client_ip(req.peer_addr(), req.headers())),
+            user_agent: req
+                .headers()
+                .get("user-agent")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.chars().take(MAX_USER_AGENT_LEN).collect()),
+        }
+    }
+
+    // Changes made with trove-admin on the server itself
+    pub fn command_line() -> Origin {
+        Origin {
+            ip: None,
+            user_agent: Some(String::from("trove-admin")),
+        }
+    }
+}
+
+pub struct Event {
+    pub action: &'static str,
+    pub user_id: Option<i32>,
+    pub actor_id: Option<i32>,
+    pub details: Option<serde_json::Value>,
+}
+
+impl Event {
+    // Something a user did to their own account
+    pub fn by_user(action: &'static str, user_id: i32) -> Event {
+        Event {
+            action,
+            user_id: Some(user_id),
+            actor_id: Some(user_id),
+            details: None,
+        }
+    }
+
+    pub fn by_admin(action: &'static str, admin_id: i32, user_id: i32) -> Event {
+        Event {
+            action,
+            user_id: Some(user_id),
+            actor_id: Some(admin_id),
+            details: None,
+        }
+    }
+
+    // Done with trove-admin, which acts without an account of its own
+    pub fn by_operator(action: &'static str, user_id: i32) -> Event {
+        Event {
+            action,
+            user_id: Some(user_id),
+            actor_id: None,
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Event {
+        self.details = Some(details);
+        self
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub user_id: Option<i32>,
+    pub action: Option<String>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// Stores an event. Failing to do so is logged, but doesn't fail the request it is about.
+pub async fn record(db: web::Data<Pool>, origin: Origin, event: Event) {
+    let recorded = logging::db_block("db_add_event", move || db_add_event(db, &origin, event));
+    if let Err(e) = recorded.await {
+        tracing::error!(error = ?e, "Failed to record audit event");
+    }
+}
+
+// Handler for GET /v1/user/audit
+pub async fn get_user_events(
+    db: web::Data<Pool>,
+    auth: BearerAuth,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    let mut query = query.into_inner();
+    query.user_id = Some(user.id);
+    Ok(logging::db_block("db_get_events", move || db_get_events(db, query))
+        .await
+        .map(|events| HttpResponse::Ok().json(events))
+        .map_err(ServiceError::from)?)
+}
+
+// Handler for GET /admin/audit
+pub async fn list_events(
+    db: web::Data<Pool>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    Ok(logging::db_block("db_get_events", move || db_get_events(db, query))
+        .await
+        .map(|events| HttpResponse::Ok().json(events))
+        .map_err(ServiceError::from)?)
+}
+
+fn db_add_event(pool: web::Data<Pool>, origin: &Origin, event: Event) -> Result<(), ServiceError> {
+    let conn = pool.get()?;
+    Ok(add_event(&conn, origin, event)?)
+}
+
+// For callers that want the event in the same transaction as the change it records
+pub fn add_event(
+    conn: &PgConnection,
+    origin: &Origin,
+    event: Event,
+) -> Result<(), diesel::result::Error> {
+    use schema::audit_event::dsl as ae;
+    diesel::insert_into(ae::audit_event)
+        .values(&NewAuditEvent {
+            user_id_fk: event.user_id,
+            actor_id: event.actor_id,
+            action: event.action,
+            ip: origin.ip.as_deref(),
+            user_agent: origin.user_agent.as_deref(),
+            details: event.details,
+            created_at: chrono::Local::now().naive_local(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+// Newest first
+fn db_get_events(pool: web::Data<Pool>, query: AuditQuery) -> Result<Vec<AuditEvent>, ServiceError> {
+    use schema::audit_event::dsl as ae;
+    let conn = pool.get()?;
+    let mut events = ae::audit_event.into_boxed();
+    if let Some(user_id) = query.user_id {
+        events = events.filter(ae::user_id_fk.eq(user_id));
+    }
+    if let Some(action) = query.action {
+        events = events.filter(ae::action.eq(action));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE);
+    Ok(events
+        .order_by(ae::id.desc())
+        .offset(query.offset.unwrap_or(0).max(0))
+        .limit(limit)
+        .load(&conn)?)
+}
+
+// Everything recorded about a user, oldest first, for the data export
+pub fn db_get_all_user_events(
+    conn: &PgConnection,
+    user_id: i32,
+) -> Result<Vec<AuditEvent>, diesel::result::Error> {
+    use schema::audit_event::dsl as ae;
+    ae::audit_event
+        .filter(ae::user_id_fk.eq(user_id))
+        .order_by(ae::id)
+        .load(conn)
+}
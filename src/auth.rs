@@ -1,27 +1,221 @@
 use crate::errors::ServiceError;
-use crate::handlers::{db_get_user_by_api_token, db_is_token_revoked};
-use crate::Pool;
+use crate::handlers::{
+    db_get_or_create_oidc_user, db_get_user_by_api_token, db_get_user_by_id, db_is_token_revoked,
+};
+use crate::models::User;
+use crate::{logging, oidc, vars, Pool};
 use actix_web::web;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{Deserialize, Serialize};
 
-pub fn validate_token(token: BearerAuth, pool: web::Data<Pool>) -> Result<bool, ServiceError> {
-    match db_is_token_revoked(pool.clone(), token.clone()) {
-        Ok(is_revoked) => {
-            if is_revoked {
-                return Err(ServiceError::AuthenticationError(String::from(
-                    "Token has revoked access",
-                )));
-            }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// API tokens are sent base64 encoded, which never contains a '.', while a JWT always does
+pub fn is_jwt(token: &str) -> bool {
+    token.contains('.')
+}
+
+// Our own access tokens are HS256 signed, anything else has to come from the identity provider
+fn is_local_jwt(token: &str) -> bool {
+    decode_header(token)
+        .map(|header| header.alg == Algorithm::HS256)
+        .unwrap_or(false)
+}
+
+pub fn create_access_token(user_id: i32) -> Result<String, ServiceError> {
+    let now = chrono::Utc::now().timestamp();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        iss: vars::jwt_issuer(),
+        iat: now,
+        exp: now + vars::access_token_ttl(),
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(vars::secret_key().as_bytes()),
+    )
+    .map_err(|_| ServiceError::InternalServerError)
+}
+
+pub fn decode_access_token(token: &str) -> Result<Claims, ServiceError> {
+    let mut validation = Validation::default();
+    validation.iss = Some(vars::jwt_issuer());
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(vars::secret_key().as_bytes()),
+        &validation,
+    )
+    .map(|data| data.claims)
+    .map_err(|_| ServiceError::AuthenticationError(String::from("Invalid access token")))
+}
+
+// Resolving a token queries the database and may fetch the identity provider's keys,
+// so both run on a blocking thread
+pub async fn validate_token(
+    token: BearerAuth,
+    pool: web::Data<Pool>,
+) -> Result<User, ServiceError> {
+    logging::db_block("validate_token", move || {
+        if is_jwt(token.token()) {
+            return jwt_user(pool, token.token());
         }
-        Err(_) => return Err(ServiceError::AuthenticationError(String::from("No token"))),
-    }
-    match db_get_user_by_api_token(pool, token) {
-        Ok(_) => {}
-        Err(_) => {
+        if db_is_token_revoked(pool.clone(), token.clone())? {
             return Err(ServiceError::AuthenticationError(String::from(
-                "No user for token",
-            )))
+                "Token has revoked access",
+            )));
+        }
+        db_get_user_by_api_token(pool, token).map_err(no_user)
+    })
+    .await
+    .map_err(ServiceError::from)
+}
+
+// Resolves the user behind a bearer credential, which is either a JWT access token or an API token
+pub async fn authenticated_user(
+    pool: web::Data<Pool>,
+    token: BearerAuth,
+) -> Result<User, ServiceError> {
+    logging::db_block("authenticated_user", move || {
+        if is_jwt(token.token()) {
+            return jwt_user(pool, token.token());
+        }
+        db_get_user_by_api_token(pool, token).map_err(no_user)
+    })
+    .await
+    .map_err(ServiceError::from)
+}
+
+// A missing user means the credential is no good, any other failure is passed on
+fn no_user(error: ServiceError) -> ServiceError {
+    match error {
+        ServiceError::NotFound(_) => {
+            ServiceError::AuthenticationError(String::from("No user for token"))
+        }
+        other => other,
+    }
+}
+
+// Users signing in through the identity provider are linked to a confirmed account with their
+// email, or get an account on first use
+fn jwt_user(pool: web::Data<Pool>, token: &str) -> Result<User, ServiceError> {
+    if is_local_jwt(token) {
+        let claims = decode_access_token(token)?;
+        let user_id = claims
+            .sub
+            .parse::<i32>()
+            .map_err(|_| ServiceError::AuthenticationError(String::from("Invalid access token")))?;
+        return db_get_user_by_id(pool, user_id).map_err(no_user);
+    }
+    if !oidc::enabled() {
+        return Err(ServiceError::AuthenticationError(String::from(
+            "Invalid access token",
+        )));
+    }
+    let identity = oidc::validate_identity(token)?;
+    db_get_or_create_oidc_user(pool, &identity).map_err(no_user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use actix_web::dev::Payload;
+    use actix_web::{test, FromRequest};
+    use diesel::r2d2::ConnectionManager;
+
+    fn claims(user_id: i32) -> Claims {
+        let now = chrono::Utc::now().timestamp();
+        Claims {
+            sub: user_id.to_string(),
+            iss: vars::jwt_issuer(),
+            iat: now,
+            exp: now + 300,
+        }
+    }
+
+    fn sign(claims: &Claims, secret: &str) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::default(), claims, &key).unwrap()
+    }
+
+    // Signed the way the identity provider signs its tokens
+    fn provider_token() -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(String::from("test-key"));
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("../tests/fixtures/oidc_private_key.pem"))
+                .unwrap();
+        encode(&header, &claims(1), &key).unwrap()
+    }
+
+    async fn bearer(token: &str) -> BearerAuth {
+        let req = test::TestRequest::with_header("Authorization", format!("Bearer {}", token))
+            .to_http_request();
+        BearerAuth::from_request(&req, &mut Payload::None)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn tells_local_tokens_from_provider_tokens() {
+        config::init_for_tests();
+        let local = create_access_token(1).unwrap();
+        assert!(is_jwt(&local));
+        assert!(is_local_jwt(&local));
+        let external = provider_token();
+        assert!(is_jwt(&external));
+        assert!(!is_local_jwt(&external));
+        assert!(!is_local_jwt("not.a.jwt"));
+        assert!(!is_jwt("dHJvdmUtYXBpLXRva2Vu"));
+    }
+
+    #[test]
+    fn access_tokens_carry_the_user() {
+        config::init_for_tests();
+        let claims = decode_access_token(&create_access_token(42).unwrap()).unwrap();
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.iss, vars::jwt_issuer());
+        assert_eq!(claims.exp - claims.iat, vars::access_token_ttl());
+    }
+
+    #[test]
+    fn rejects_forged_expired_and_foreign_access_tokens() {
+        config::init_for_tests();
+        assert!(decode_access_token(&sign(&claims(1), "another-secret-key")).is_err());
+
+        let mut expired = claims(1);
+        expired.exp = chrono::Utc::now().timestamp() - 300;
+        assert!(decode_access_token(&sign(&expired, &vars::secret_key())).is_err());
+
+        let mut foreign = claims(1);
+        foreign.iss = String::from("another-server");
+        assert!(decode_access_token(&sign(&foreign, &vars::secret_key())).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn validate_token_rejects_bad_jwts_without_the_database() {
+        config::init_for_tests();
+        let pool: Pool =
+            Pool::builder().build_unchecked(ConnectionManager::new(vars::database_url()));
+        let pool = web::Data::new(pool);
+        // Provider tokens aren't accepted while OIDC isn't configured
+        for token in [sign(&claims(1), "another-secret-key"), provider_token()] {
+            let result = validate_token(bearer(&token).await, pool.clone()).await;
+            assert!(
+                matches!(result, Err(ServiceError::AuthenticationError(_))),
+                "{:?}",
+                result
+            );
         }
     }
-    Ok(true)
 }
@@ -0,0 +1,276 @@
+use actix_web::web;
+use diesel::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+use std::process;
+use trove_server::admin::db_get_stats;
+use trove_server::audit::{self, Event, Origin};
+use trove_server::errors::ServiceError;
+use trove_server::export::write_export;
+use trove_server::handlers::{db_get_user_by_email, revoke_user_tokens};
+use trove_server::models::{NewTrove, NewUser, User};
+use trove_server::{config, logging, schema, subscription, utils, vars, Pool};
+
+const USAGE: &str = "Usage: trove-admin <command>
+
+Commands:
+  migrate                       Run pending database migrations
+  create-admin <email>          Create an admin, or make an existing user one
+  reset-password <email>        Set a new password and end all sessions of a user
+  revoke-tokens <email>         Revoke all API and refresh tokens of a user
+  export <email> <file.tar>     Write the data export of a user to a file
+  import <email> <file.tar>     Add the troves of a data export to a user
+  stats                         Print user and trove counts
+  sign-webhook <event.json>     Print the signature header for a payment event
+
+Passwords are read from the first line of stdin.
+Settings come from the environment and CONFIG_FILE, like they do for the server.";
+
+#[derive(Deserialize)]
+struct ExportedRevision {
+    created_at: chrono::NaiveDateTime,
+    file: String,
+}
+
+fn main() {
+    match config::Config::load(&[]) {
+        Ok(config) => {
+            // Migrations report progress through the log
+            logging::init(&config);
+            config::init(config);
+        }
+        Err(message) => {
+            eprintln!("Invalid configuration:\n{}", message);
+            process::exit(2);
+        }
+    }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["migrate"] => migrate(),
+        ["create-admin", user_email] => create_admin(user_email),
+        ["reset-password", user_email] => reset_password(user_email),
+        ["revoke-tokens", user_email] => revoke_tokens(user_email),
+        ["export", user_email, path] => export(user_email, path),
+        ["import", user_email, path] => import(user_email, path),
+        ["stats"] => stats(),
+        ["sign-webhook", path] => sign_webhook(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(message) = result {
+        eprintln!("Error: {}", message);
+        process::exit(1);
+    }
+}
+
+fn pool() -> web::Data<Pool> {
+    web::Data::new(trove_server::create_pool())
+}
+
+fn find_user(pool: &web::Data<Pool>, user_email: &str) -> Result<User, String> {
+    let user_email = utils::normalize_email(user_email)?;
+    db_get_user_by_email(pool.clone(), &user_email).map_err(|e| match e {
+        ServiceError::NotFound(_) => format!("No user with email {}", user_email),
+        e => e.to_string(),
+    })
+}
+
+fn read_password(user_email: &str) -> Result<String, String> {
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("Could not read password: {}", e))?;
+    let password = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+    utils::check_password_policy(&password, user_email)?;
+    Ok(password)
+}
+
+fn migrate() -> Result<(), String> {
+    trove_server::migrate::run(&pool(), true)?;
+    println!("Migrations are up to date");
+    Ok(())
+}
+
+fn create_admin(user_email: &str) -> Result<(), String> {
+    use schema::users::dsl as us;
+    let pool = pool();
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    let user_email = utils::normalize_email(user_email)?;
+    let existing: Option<User> = us::users
+        .filter(us::email.eq(&user_email))
+        .get_result(&conn)
+        .optional()
+        .map_err(|e| e.to_string())?;
+    if let Some(user) = existing {
+        conn.transaction(|| {
+            diesel::update(us::users.find(user.id))
+                .set(us::admin.eq(true))
+                .execute(&conn)?;
+            audit::add_event(
+                &conn,
+                &Origin::command_line(),
+                Event::by_operator(audit::ADMIN_UPDATE_USER, user.id)
+                    .with_details(serde_json::json!({ "admin": true })),
+            )
+        })
+        .map_err(|e| e.to_string())?;
+        println!("Made {} an admin", user_email);
+        return Ok(());
+    }
+    let password = read_password(&user_email)?;
+    let hashed_password = utils::hash_password(&password).map_err(|e| e.to_string())?;
+    let now = chrono::Local::now().naive_local();
+    let user: User = conn
+        .transaction(|| {
+            let user: User = diesel::insert_into(us::users)
+                .values(&NewUser {
+                    email: &user_email,
+                    pw_hash: &hashed_password,
+                    verified: true,
+                    subscribed: false,
+                    created_at: now,
+                    last_payment: now,
+                    admin: true,
+                })
+                .get_result(&conn)?;
+            audit::add_event(
+                &conn,
+                &Origin::command_line(),
+                Event::by_operator(audit::ADMIN_CREATE_USER, user.id)
+                    .with_details(serde_json::json!({ "admin": true })),
+            )?;
+            Ok(user)
+        })
+        .map_err(|e: diesel::result::Error| e.to_string())?;
+    println!("Created admin {} with id {}", user_email, user.id);
+    Ok(())
+}
+
+fn reset_password(user_email: &str) -> Result<(), String> {
+    use schema::users::dsl as us;
+    let pool = pool();
+    let user = find_user(&pool, user_email)?;
+    let password = read_password(&user.email)?;
+    let hashed_password = utils::hash_password(&password).map_err(|e| e.to_string())?;
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    conn.transaction(|| {
+        diesel::update(us::users.find(user.id))
+            .set(us::pw_hash.eq(&hashed_password))
+            .execute(&conn)?;
+        revoke_user_tokens(&conn, user.id, None)?;
+        audit::add_event(
+            &conn,
+            &Origin::command_line(),
+            Event::by_operator(audit::ADMIN_RESET_PASSWORD, user.id),
+        )
+    })
+    .map_err(|e| e.to_string())?;
+    println!("Reset the password of {} and revoked their tokens", user.email);
+    Ok(())
+}
+
+fn revoke_tokens(user_email: &str) -> Result<(), String> {
+    let pool = pool();
+    let user = find_user(&pool, user_email)?;
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    conn.transaction(|| {
+        revoke_user_tokens(&conn, user.id, None)?;
+        audit::add_event(
+            &conn,
+            &Origin::command_line(),
+            Event::by_operator(audit::ADMIN_REVOKE_TOKENS, user.id),
+        )
+    })
+    .map_err(|e| e.to_string())?;
+    println!("Revoked all tokens of {}", user.email);
+    Ok(())
+}
+
+fn export(user_email: &str, path: &str) -> Result<(), String> {
+    let pool = pool();
+    let user = find_user(&pool, user_email)?;
+    let file =
+        std::fs::File::create(path).map_err(|e| format!("Could not write {}: {}", path, e))?;
+    write_export(pool, &user, io::BufWriter::new(file)).map_err(|e| e.to_string())?;
+    println!("Exported {} to {}", user.email, path);
+    Ok(())
+}
+
+// Adds the trove revisions of an export as new revisions, keeping their order and dates
+fn import(user_email: &str, path: &str) -> Result<(), String> {
+    use schema::trove::dsl as tr;
+    let pool = pool();
+    let user = find_user(&pool, user_email)?;
+    let file = std::fs::File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    let mut files = HashMap::new();
+    let mut archive = tar::Archive::new(file);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .to_string_lossy()
+            .into_owned();
+        let mut content = String::new();
+        entry
+            .read_to_string(&mut content)
+            .map_err(|e| format!("Could not read {}: {}", name, e))?;
+        files.insert(name, content);
+    }
+    let index = files
+        .get("troves.json")
+        .ok_or_else(|| String::from("troves.json is missing, this is no data export"))?;
+    let revisions: Vec<ExportedRevision> =
+        serde_json::from_str(index).map_err(|e| format!("Invalid troves.json: {}", e))?;
+
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    conn.transaction::<_, ServiceError, _>(|| {
+        for revision in &revisions {
+            let text = files.get(&revision.file).ok_or_else(|| {
+                ServiceError::BadRequest(format!("{} is missing", revision.file))
+            })?;
+            diesel::insert_into(tr::trove)
+                .values(&NewTrove {
+                    trove_text: &utils::encode_text(text.clone()),
+                    user_id_fk: user.id,
+                    created_at: revision.created_at,
+                })
+                .execute(&conn)?;
+        }
+        audit::add_event(
+            &conn,
+            &Origin::command_line(),
+            Event::by_operator(audit::ADMIN_IMPORT, user.id)
+                .with_details(serde_json::json!({ "revisions": revisions.len() })),
+        )?;
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    println!("Imported {} troves for {}", revisions.len(), user.email);
+    Ok(())
+}
+
+fn stats() -> Result<(), String> {
+    let stats = db_get_stats(pool()).map_err(|e| e.to_string())?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&stats).map_err(|e| e.to_string())?
+    );
+    Ok(())
+}
+
+// Stands in for the payment provider when testing the webhook locally
+fn sign_webhook(path: &str) -> Result<(), String> {
+    let secret = vars::payment_webhook_secret()
+        .ok_or_else(|| String::from("PAYMENT_WEBHOOK_SECRET is not set"))?;
+    let body = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let header = subscription::signature_header(&secret, chrono::Utc::now().timestamp(), &body)
+        .map_err(|e| e.to_string())?;
+    println!("{}: {}", subscription::SIGNATURE_HEADER, header);
+    Ok(())
+}
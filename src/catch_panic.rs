@@ -0,0 +1,105 @@
+use crate::errors::ServiceError;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::Error;
+use futures::future::{ok, FutureExt, Ready};
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Middleware turning a panic while handling a request into a 500 instead of taking the worker down.
+// Wrapped inside `RequestIdentity`, so the error body carries the request id.
+pub struct CatchPanic;
+
+impl<S, B> Transform<S> for CatchPanic
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CatchPanicMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CatchPanicMiddleware { service })
+    }
+}
+
+pub struct CatchPanicMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CatchPanicMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = &mut self.service;
+        let fut = match catch_unwind(AssertUnwindSafe(|| service.call(req))) {
+            Ok(fut) => fut,
+            Err(_) => return Box::pin(async { Err(ServiceError::InternalServerError.into()) }),
+        };
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(res) => res,
+                Err(_) => Err(ServiceError::InternalServerError.into()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id::RequestIdentity;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn panics() -> HttpResponse {
+        panic!("Handler failed")
+    }
+
+    async fn works() -> HttpResponse {
+        HttpResponse::Ok().json("ok")
+    }
+
+    #[actix_rt::test]
+    async fn answers_panics_with_500_and_keeps_serving() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(CatchPanic)
+                .wrap(RequestIdentity)
+                .route("/panic", web::get().to(panics))
+                .route("/ok", web::get().to(works)),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/panic").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["code"], "internal_error");
+            assert!(body["request_id"].is_string());
+
+            let req = test::TestRequest::get().uri("/ok").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::errors::ServiceError;
+use crate::schema;
+use crate::vars;
+use diesel::prelude::*;
+
+// Date at which an account scheduled for deletion at `deleted_at` gets purged
+pub fn purge_date(deleted_at: chrono::NaiveDateTime) -> chrono::NaiveDateTime {
+    deleted_at + chrono::Duration::seconds(vars::account_deletion_grace_period())
+}
+
+// Removes a user and everything referencing them. Runs in one transaction, so a failure
+// leaves the account untouched instead of half deleted.
+// There is no sharing of troves between users yet, so nothing outside the user's own rows is touched.
+pub fn purge_user(conn: &PgConnection, user_id: i32) -> Result<(), diesel::result::Error> {
+    use schema::account_lockout::dsl as al;
+    use schema::api_token::dsl as at;
+    use schema::audit_event::dsl as ae;
+    use schema::payment_event::dsl as pe;
+    use schema::recovery_code::dsl as rc;
+    use schema::refresh_token::dsl as rt;
+    use schema::totp_secret::dsl as ts;
+    use schema::trove::dsl as tr;
+    use schema::users::dsl as us;
+    use schema::verification_token::dsl as vt;
+    conn.transaction(|| {
+        diesel::delete(rc::recovery_code.filter(rc::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(ts::totp_secret.filter(ts::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(al::account_lockout.filter(al::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(vt::verification_token.filter(vt::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(rt::refresh_token.filter(rt::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(at::api_token.filter(at::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(tr::trove.filter(tr::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(pe::payment_event.filter(pe::user_id_fk.eq(user_id))).execute(conn)?;
+        // Events this user caused on other accounts as an admin keep existing without an actor
+        diesel::delete(ae::audit_event.filter(ae::user_id_fk.eq(user_id))).execute(conn)?;
+        diesel::delete(us::users.find(user_id)).execute(conn)?;
+        Ok(())
+    })
+}
+
+// Purges every account whose grace period ran out, returns how many were removed.
+// Each account is purged in its own transaction, a failing one does not hold up the rest.
+pub fn purge_expired_accounts(conn: &PgConnection) -> Result<usize, ServiceError> {
+    use schema::users::dsl as us;
+    let cutoff = chrono::Local::now().naive_local()
+        - chrono::Duration::seconds(vars::account_deletion_grace_period());
+    let expired: Vec<i32> = us::users
+        .select(us::id)
+        .filter(us::deleted_at.lt(cutoff))
+        .load(conn)?;
+    let mut purged = 0;
+    for user_id in expired {
+        match purge_user(conn, user_id) {
+            Ok(()) => purged += 1,
+            Err(e) => tracing::error!(user_id, error = %e, "Failed to purge user"),
+        }
+    }
+    Ok(purged)
+}
@@ -1,5 +1,10 @@
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
 use actix_web::{error::ResponseError, HttpResponse};
 use derive_more::Display;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+use std::fmt::Debug;
 
 #[allow(dead_code)]
 #[derive(Debug, Display)]
@@ -18,25 +23,193 @@ pub enum ServiceError {
 
     #[display(fmt = "RegistrationError: {}", _0)]
     RegistrationError(String),
+
+    #[display(fmt = "Forbidden: {}", _0)]
+    Forbidden(String),
+
+    #[display(fmt = "TooManyRequests: retry after {}s", _0)]
+    TooManyRequests(u64),
+
+    #[display(fmt = "PayloadTooLarge: {}", _0)]
+    PayloadTooLarge(String),
+
+    #[display(fmt = "NotFound: {}", _0)]
+    NotFound(String),
+
+    #[display(fmt = "Conflict: {}", _0)]
+    Conflict(String),
+
+    #[display(fmt = "ValidationError: {}", _0)]
+    ValidationError(String),
 }
 
-// impl ResponseError trait allows to convert our errors into http responses with appropriate data
-impl ResponseError for ServiceError {
-    fn error_response(&self) -> HttpResponse {
+// Body of every error response. `code` is stable and meant for clients to match on,
+// `message` is for humans and may change.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+    pub request_id: Option<String>,
+}
+
+impl ServiceError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServiceError::InternalServerError => "internal_error",
+            ServiceError::BadRequest(_) => "bad_request",
+            ServiceError::JWKSFetchError => "jwks_fetch_failed",
+            ServiceError::AuthenticationError(_) => "unauthorized",
+            ServiceError::RegistrationError(_) => "registration_failed",
+            ServiceError::Forbidden(_) => "forbidden",
+            ServiceError::TooManyRequests(_) => "too_many_requests",
+            ServiceError::PayloadTooLarge(_) => "payload_too_large",
+            ServiceError::NotFound(_) => "not_found",
+            ServiceError::Conflict(_) => "conflict",
+            ServiceError::ValidationError(_) => "validation_failed",
+        }
+    }
+
+    pub fn message(&self) -> String {
         match self {
             ServiceError::InternalServerError => {
-                HttpResponse::InternalServerError().json("Internal Server Error, Please try later")
+                String::from("Internal Server Error, Please try later")
             }
-            ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
-            ServiceError::JWKSFetchError => {
-                HttpResponse::InternalServerError().json("Could not fetch JWKS")
+            ServiceError::JWKSFetchError => String::from("Could not fetch JWKS"),
+            ServiceError::TooManyRequests(retry_after) => {
+                format!("Too many requests, retry in {} seconds", retry_after)
             }
-            ServiceError::AuthenticationError(ref message) => {
-                HttpResponse::BadRequest().json(message)
+            ServiceError::BadRequest(ref message)
+            | ServiceError::AuthenticationError(ref message)
+            | ServiceError::RegistrationError(ref message)
+            | ServiceError::Forbidden(ref message)
+            | ServiceError::PayloadTooLarge(ref message)
+            | ServiceError::NotFound(ref message)
+            | ServiceError::Conflict(ref message)
+            | ServiceError::ValidationError(ref message) => message.clone(),
+        }
+    }
+
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            ServiceError::TooManyRequests(retry_after) => {
+                Some(serde_json::json!({ "retry_after": retry_after }))
             }
-            ServiceError::RegistrationError(ref message) => {
-                HttpResponse::BadRequest().json(message)
+            _ => None,
+        }
+    }
+
+    pub fn body(&self, request_id: Option<String>) -> ErrorBody {
+        ErrorBody {
+            code: self.code().to_string(),
+            message: self.message(),
+            details: self.details(),
+            request_id,
+        }
+    }
+
+    // Same as `error_response`, with the id of the failed request in the body
+    pub fn response_with_request_id(&self, request_id: Option<String>) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let ServiceError::TooManyRequests(retry_after) = self {
+            res.header("Retry-After", retry_after.to_string());
+        }
+        res.json(self.body(request_id))
+    }
+}
+
+// impl ResponseError trait allows to convert our errors into http responses with appropriate data
+impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::JWKSFetchError => StatusCode::BAD_GATEWAY,
+            ServiceError::AuthenticationError(_) => StatusCode::UNAUTHORIZED,
+            ServiceError::RegistrationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::Conflict(_) => StatusCode::CONFLICT,
+            ServiceError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.response_with_request_id(None)
+    }
+}
+
+impl From<DieselError> for ServiceError {
+    fn from(error: DieselError) -> ServiceError {
+        match error {
+            DieselError::NotFound => ServiceError::NotFound(String::from("Not found")),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                ServiceError::Conflict(String::from("Already exists"))
             }
+            _ => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<r2d2::Error> for ServiceError {
+    fn from(_: r2d2::Error) -> ServiceError {
+        ServiceError::InternalServerError
+    }
+}
+
+// Errors of `web::block` closures keep their meaning, a canceled closure is an internal error
+impl<E> From<BlockingError<E>> for ServiceError
+where
+    E: Into<ServiceError> + Debug,
+{
+    fn from(error: BlockingError<E>) -> ServiceError {
+        match error {
+            BlockingError::Error(e) => e.into(),
+            BlockingError::Canceled => ServiceError::InternalServerError,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_every_variant_to_its_status_and_code() {
+        use ServiceError::*;
+        let message = || String::from("message");
+        let cases = vec![
+            (InternalServerError, 500, "internal_error"),
+            (BadRequest(message()), 400, "bad_request"),
+            (JWKSFetchError, 502, "jwks_fetch_failed"),
+            (AuthenticationError(message()), 401, "unauthorized"),
+            (RegistrationError(message()), 422, "registration_failed"),
+            (Forbidden(message()), 403, "forbidden"),
+            (TooManyRequests(30), 429, "too_many_requests"),
+            (PayloadTooLarge(message()), 413, "payload_too_large"),
+            (NotFound(message()), 404, "not_found"),
+            (Conflict(message()), 409, "conflict"),
+            (ValidationError(message()), 422, "validation_failed"),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!(error.status_code().as_u16(), status, "{:?}", error);
+            assert_eq!(error.code(), code, "{:?}", error);
+            let res = error.response_with_request_id(Some(String::from("abc")));
+            assert_eq!(res.status().as_u16(), status, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn too_many_requests_tells_when_to_retry() {
+        let error = ServiceError::TooManyRequests(30);
+        let res = error.error_response();
+        assert_eq!(res.headers().get("Retry-After").unwrap(), "30");
+        assert_eq!(
+            error.details(),
+            Some(serde_json::json!({ "retry_after": 30 }))
+        );
+        assert_eq!(error.message(), "Too many requests, retry in 30 seconds");
+    }
+}
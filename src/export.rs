@@ -0,0 +1,330 @@
+use crate::audit::db_get_all_user_events;
+use crate::errors::ServiceError;
+use crate::logging;
+use crate::models::{APIToken, AuditEvent, RefreshToken, Trove, User, UserProfile};
+use crate::schema;
+use crate::utils::decode_text;
+use crate::Pool;
+use actix_web::web::{self, Bytes};
+use diesel::prelude::*;
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream};
+use serde::Serialize;
+use std::io::Write;
+
+// Size of the pieces an export is streamed in, and how many of them may wait for a slow client
+const CHUNK_SIZE: usize = 64 * 1024;
+const BUFFERED_CHUNKS: usize = 4;
+// Trove revisions loaded from the database at once
+const REVISION_BATCH: usize = 50;
+
+#[derive(Serialize)]
+struct ApiTokenMetadata {
+    id: i32,
+    revoked: bool,
+    created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct RefreshTokenMetadata {
+    id: i32,
+    used: bool,
+    revoked: bool,
+    created_at: chrono::NaiveDateTime,
+    expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct TokenMetadata {
+    api_tokens: Vec<ApiTokenMetadata>,
+    refresh_tokens: Vec<RefreshTokenMetadata>,
+}
+
+#[derive(Serialize)]
+struct TroveRevision {
+    id: i32,
+    created_at: chrono::NaiveDateTime,
+    file: String,
+}
+
+// What an export needs besides the trove revisions, which are loaded in batches
+struct ExportData {
+    api_tokens: Vec<APIToken>,
+    refresh_tokens: Vec<RefreshToken>,
+    trove_ids: Vec<i32>,
+    audit_events: Vec<AuditEvent>,
+}
+
+// The export of a user as a response body. Only the database reads run on blocking threads, each
+// with a connection of its own, so a slow download holds neither while it waits for the client.
+pub fn stream_export(
+    pool: web::Data<Pool>,
+    user: User,
+) -> impl Stream<Item = Result<Bytes, ServiceError>> + Unpin {
+    let (mut sender, body) = mpsc::channel(BUFFERED_CHUNKS);
+    actix_rt::spawn(async move {
+        if let Err(e) = send_export(pool, &user, &mut sender).await {
+            tracing::error!(error = ?e, "Export failed");
+            // Ends the download with an error instead of a truncated archive that looks complete
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+    body
+}
+
+async fn send_export(
+    pool: web::Data<Pool>,
+    user: &User,
+    sender: &mut mpsc::Sender<Result<Bytes, ServiceError>>,
+) -> Result<(), ServiceError> {
+    let db = pool.clone();
+    let user_id = user.id;
+    let data = logging::db_block("db_get_export_data", move || {
+        db_get_export_data(db, user_id)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let mut archive = Archive::new(Vec::with_capacity(CHUNK_SIZE));
+    add_account(&mut archive, user, &data)?;
+    let mut revisions = Vec::with_capacity(data.trove_ids.len());
+    for batch in data.trove_ids.chunks(REVISION_BATCH) {
+        if !send_written(&mut archive, sender).await {
+            return Ok(());
+        }
+        let db = pool.clone();
+        let batch = batch.to_vec();
+        let troves = logging::db_block("db_get_troves", move || db_get_troves(db, &batch))
+            .await
+            .map_err(ServiceError::from)?;
+        add_troves(&mut archive, troves, &mut revisions)?;
+    }
+    add_index(&mut archive, &revisions, &data)?;
+    let rest = archive.finish()?;
+    send_chunks(Bytes::from(rest), sender).await;
+    Ok(())
+}
+
+// Hands what the archive has written so far to the response body, false once the client went away
+async fn send_written(
+    archive: &mut Archive<Vec<u8>>,
+    sender: &mut mpsc::Sender<Result<Bytes, ServiceError>>,
+) -> bool {
+    let written = std::mem::take(archive.builder.get_mut());
+    send_chunks(Bytes::from(written), sender).await
+}
+
+// Waits while the client is behind, in pieces of `CHUNK_SIZE`
+async fn send_chunks(
+    mut data: Bytes,
+    sender: &mut mpsc::Sender<Result<Bytes, ServiceError>>,
+) -> bool {
+    while !data.is_empty() {
+        let chunk = data.split_to(CHUNK_SIZE.min(data.len()));
+        if sender.send(Ok(chunk)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// Writes a tar archive of everything stored about a user. Token values and the password hash
+// are left out, trove revisions are decoded back into the files hoard uploaded.
+pub fn write_export<W: Write>(
+    pool: web::Data<Pool>,
+    user: &User,
+    out: W,
+) -> Result<(), ServiceError> {
+    let data = db_get_export_data(pool.clone(), user.id)?;
+    let mut archive = Archive::new(out);
+    add_account(&mut archive, user, &data)?;
+    let mut revisions = Vec::with_capacity(data.trove_ids.len());
+    for batch in data.trove_ids.chunks(REVISION_BATCH) {
+        add_troves(
+            &mut archive,
+            db_get_troves(pool.clone(), batch)?,
+            &mut revisions,
+        )?;
+    }
+    add_index(&mut archive, &revisions, &data)?;
+    archive.finish()?;
+    Ok(())
+}
+
+fn db_get_export_data(pool: web::Data<Pool>, user_id: i32) -> Result<ExportData, ServiceError> {
+    use schema::api_token::dsl as at;
+    use schema::refresh_token::dsl as rt;
+    use schema::trove::dsl as tr;
+    let conn = pool.get()?;
+    Ok(ExportData {
+        api_tokens: at::api_token
+            .filter(at::user_id_fk.eq(user_id))
+            .order_by(at::id)
+            .load(&conn)?,
+        refresh_tokens: rt::refresh_token
+            .filter(rt::user_id_fk.eq(user_id))
+            .order_by(rt::id)
+            .load(&conn)?,
+        trove_ids: tr::trove
+            .filter(tr::user_id_fk.eq(user_id))
+            .order_by(tr::id)
+            .select(tr::id)
+            .load(&conn)?,
+        audit_events: db_get_all_user_events(&conn, user_id)?,
+    })
+}
+
+fn db_get_troves(pool: web::Data<Pool>, ids: &[i32]) -> Result<Vec<Trove>, ServiceError> {
+    use schema::trove::dsl as tr;
+    let conn = pool.get()?;
+    Ok(tr::trove
+        .filter(tr::id.eq_any(ids))
+        .order_by(tr::id)
+        .load(&conn)?)
+}
+
+fn add_account<W: Write>(
+    archive: &mut Archive<W>,
+    user: &User,
+    data: &ExportData,
+) -> Result<(), ServiceError> {
+    archive.add_json("profile.json", &UserProfile::from(user))?;
+    archive.add_json(
+        "tokens.json",
+        &TokenMetadata {
+            api_tokens: data
+                .api_tokens
+                .iter()
+                .map(|t| ApiTokenMetadata {
+                    id: t.id,
+                    revoked: t.revoked,
+                    created_at: t.created_at,
+                })
+                .collect(),
+            refresh_tokens: data
+                .refresh_tokens
+                .iter()
+                .map(|t| RefreshTokenMetadata {
+                    id: t.id,
+                    used: t.used,
+                    revoked: t.revoked,
+                    created_at: t.created_at,
+                    expires_at: t.expires_at,
+                })
+                .collect(),
+        },
+    )
+}
+
+fn add_troves<W: Write>(
+    archive: &mut Archive<W>,
+    troves: Vec<Trove>,
+    revisions: &mut Vec<TroveRevision>,
+) -> Result<(), ServiceError> {
+    for t in troves {
+        let file = format!("troves/{}.yml", t.id);
+        archive.add_file(&file, decode_text(t.trove_text)?.as_bytes())?;
+        revisions.push(TroveRevision {
+            id: t.id,
+            created_at: t.created_at,
+            file,
+        });
+    }
+    Ok(())
+}
+
+fn add_index<W: Write>(
+    archive: &mut Archive<W>,
+    revisions: &[TroveRevision],
+    data: &ExportData,
+) -> Result<(), ServiceError> {
+    archive.add_json("troves.json", &revisions)?;
+    archive.add_json("audit.json", &data.audit_events)
+}
+
+struct Archive<W: Write> {
+    builder: tar::Builder<W>,
+    mtime: u64,
+}
+
+impl<W: Write> Archive<W> {
+    fn new(out: W) -> Archive<W> {
+        Archive {
+            builder: tar::Builder::new(out),
+            mtime: chrono::Utc::now().timestamp() as u64,
+        }
+    }
+
+    fn add_json<T: Serialize>(&mut self, path: &str, value: &T) -> Result<(), ServiceError> {
+        let content =
+            serde_json::to_vec_pretty(value).map_err(|_| ServiceError::InternalServerError)?;
+        self.add_file(path, &content)
+    }
+
+    fn add_file(&mut self, path: &str, content: &[u8]) -> Result<(), ServiceError> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_cksum();
+        self.builder
+            .append_data(&mut header, path, content)
+            .map_err(|_| ServiceError::InternalServerError)
+    }
+
+    // Returns the writer, for the part of the archive not handed on yet
+    fn finish(self) -> Result<W, ServiceError> {
+        let mut out = self
+            .builder
+            .into_inner()
+            .map_err(|_| ServiceError::InternalServerError)?;
+        out.flush().map_err(|_| ServiceError::InternalServerError)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[actix_rt::test]
+    async fn hands_on_the_archive_in_chunks() {
+        let (mut sender, body) = mpsc::channel(16);
+        let content = vec![b'a'; 2 * CHUNK_SIZE + 100];
+        let mut archive = Archive::new(Vec::new());
+        archive.add_file("troves/1.yml", &content).unwrap();
+        assert!(send_written(&mut archive, &mut sender).await);
+        archive.add_json("troves.json", &Vec::<i32>::new()).unwrap();
+        let rest = archive.finish().unwrap();
+        assert!(send_chunks(Bytes::from(rest), &mut sender).await);
+        drop(sender);
+
+        let chunks: Vec<Bytes> = body.map(|c| c.unwrap()).collect().await;
+        assert!(chunks.len() > 3);
+        assert!(chunks.iter().all(|c| c.len() <= CHUNK_SIZE));
+        let tarball: Vec<u8> = chunks.iter().flat_map(|c| c.iter().copied()).collect();
+        let mut unpacked = tar::Archive::new(&tarball[..]);
+        let sizes: Vec<(String, u64)> = unpacked
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                (e.path().unwrap().display().to_string(), e.size())
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                (String::from("troves/1.yml"), content.len() as u64),
+                (String::from("troves.json"), 2),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn stops_once_the_client_is_gone() {
+        let (mut sender, body) = mpsc::channel(BUFFERED_CHUNKS);
+        drop(body);
+        assert!(!send_chunks(Bytes::from(vec![0; CHUNK_SIZE]), &mut sender).await);
+    }
+}
@@ -1,31 +1,26 @@
 use std::io::Write;
 
 use actix_multipart::Multipart;
-use actix_web::web;
+use actix_web::{web, Error};
 use futures::{StreamExt, TryStreamExt};
 
-pub async fn save_file(mut payload: Multipart, file_path: String) -> Option<bool> {
+pub async fn save_file(mut payload: Multipart, file_path: String) -> Result<(), Error> {
     // iterate over multipart stream
     while let Ok(Some(mut field)) = payload.try_next().await {
-        println!("save_file: Here!");
         //let filename = content_type.get_filename().unwrap();
         let filepath = file_path.clone();
 
         // File::create is blocking operation, use threadpool
-        let mut f = web::block(|| std::fs::File::create(filepath))
-            .await
-            .unwrap();
+        let mut f = web::block(|| std::fs::File::create(filepath)).await?;
 
         // Field in turn is stream of *Bytes* object
         while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
-            println!("save_file: {:?}", data);
+            let data = chunk?;
+            tracing::debug!(bytes = data.len(), "Writing upload chunk");
             // filesystem operations are blocking, we have to use threadpool
-            f = web::block(move || f.write_all(&data).map(|_| f))
-                .await
-                .unwrap();
+            f = web::block(move || f.write_all(&data).map(|_| f)).await?;
         }
     }
 
-    Some(true)
+    Ok(())
 }
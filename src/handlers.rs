@@ -5,26 +5,58 @@ use std::str;
 
 use super::file::save_file;
 use super::models::{NewUser, User};
-use super::utils::{generate_api_token, verify, encode_text, decode_text};
+use super::utils::{
+    generate_api_token, generate_recovery_code, generate_refresh_token, hash_token, needs_rehash,
+    verify, encode_text, decode_text,
+};
 use super::Pool;
 use crate::diesel::QueryDsl;
 use crate::diesel::RunQueryDsl;
-use crate::models::{APIToken, NewToken, Trove, NewTrove};
+use crate::audit::{self, Event, Origin};
+use crate::auth::{authenticated_user, create_access_token, is_jwt};
+use crate::deletion;
+use crate::export;
+use crate::logging;
+use crate::mailer::SharedMailer;
+use crate::metrics;
+use crate::oidc::{self, OidcIdentity};
+use crate::models::{
+    APIToken, NewRecoveryCode, NewRefreshToken, NewToken, NewTotpSecret, NewVerificationToken,
+    PreferencesChange, RecoveryCode, RefreshToken, TotpSecret, Trove, NewTrove, UserProfile,
+    VerificationToken, SYNC_CONFLICT_STRATEGIES,
+};
+use crate::quota::{self, Limits};
+use crate::ratelimit::{throttle, Rule, SharedLimiter};
+use crate::totp;
 use crate::{errors::ServiceError, utils, vars};
 use actix_multipart::Multipart;
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use diesel::dsl::{delete, insert_into};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use diesel::{ExpressionMethods, OptionalExtension};
+use futures::StreamExt;
 use schema::api_token::dsl::*;
+use schema::refresh_token::dsl::refresh_token;
 use schema::users::dsl::*;
 use schema::trove::dsl::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs::File;
 use std::io::prelude::*;
 use std::vec::Vec;
 use utils::decode_token;
 
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+// Purpose of a verification token sent to confirm a new account
+pub const VERIFY_EMAIL: &str = "verify_email";
+// Purpose of a verification token sent to reset a forgotten password
+pub const PASSWORD_RESET: &str = "password_reset";
+// Purpose of a verification token sent to confirm a new email address
+pub const CHANGE_EMAIL: &str = "change_email";
+// Purpose of a verification token sent to confirm deleting an account
+pub const DELETE_ACCOUNT: &str = "delete_account";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InputUser {
     pub password: String,
@@ -35,6 +67,110 @@ pub struct InputUser {
 pub struct InputAuthUser {
     pub password: String,
     pub email: String,
+    // Either a current TOTP code or an unused recovery code, required once TOTP is enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputTotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputTotpDisable {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpRecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputPasswordChange {
+    pub old_password: String,
+    pub new_password: String,
+    #[serde(default)]
+    pub revoke_other_tokens: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputAccountDeletion {
+    // Without it a link confirming the deletion is mailed instead, for accounts that sign in
+    // through the identity provider and have no password of their own
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountDeletion {
+    pub deleted_at: chrono::NaiveDateTime,
+    pub purge_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputEmailChange {
+    pub password: String,
+    pub new_email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputPreferences {
+    // `null` clears these, leaving them out keeps them
+    #[serde(default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub default_trove: Option<Option<i32>>,
+    pub timezone: Option<String>,
+    pub sync_conflict_strategy: Option<String>,
+}
+
+// Tells a field set to `null` apart from a missing one, which `#[serde(default)]` turns into `None`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputPasswordReset {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputRefreshToken {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Usage {
+    pub tier: String,
+    pub limits: Limits,
+    pub trove_bytes: usize,
+    pub revisions: i64,
+    pub commands: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,34 +191,321 @@ pub async fn info() -> Result<HttpResponse, Error> {
 // Handler for Get /token
 pub async fn create_api_token(
     db: web::Data<Pool>,
+    limiter: web::Data<SharedLimiter>,
+    req: HttpRequest,
     item: web::Json<InputAuthUser>,
 ) -> Result<HttpResponse, Error> {
-    // Get User ID from DB
-    let user_email = item.email.clone();
+    let origin = Origin::of(&req);
+    let user = authenticate_credentials(db.clone(), limiter, &origin, &item).await?;
+    let user_id = user.id;
+    let db_clone = db.clone();
+    let new_token = logging::db_block("db_add_api_token", move || {
+        db_add_api_token(db_clone, user_id)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let event = Event::by_user(audit::TOKEN_CREATE, user_id)
+        .with_details(serde_json::json!({ "token_id": new_token.id }));
+    audit::record(db, origin, event).await;
+    Ok(HttpResponse::Created().json(new_token))
+}
+
+// Handler for POST /auth/login
+pub async fn login(
+    db: web::Data<Pool>,
+    limiter: web::Data<SharedLimiter>,
+    req: HttpRequest,
+    item: web::Json<InputAuthUser>,
+) -> Result<HttpResponse, Error> {
+    let origin = Origin::of(&req);
+    let user = authenticate_credentials(db.clone(), limiter, &origin, &item).await?;
+    let user_id = user.id;
+    let family = generate_refresh_token();
+    let db_clone = db.clone();
+    let new_refresh_token = logging::db_block("db_add_refresh_token", move || {
+        db_add_refresh_token(db_clone, user_id, &family)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    audit::record(db, origin, Event::by_user(audit::LOGIN, user_id)).await;
+    Ok(HttpResponse::Ok().json(token_pair(user_id, new_refresh_token)?))
+}
+
+// Checks email, password and second factor, locking the account after repeated wrong passwords
+async fn authenticate_credentials(
+    db: web::Data<Pool>,
+    limiter: web::Data<SharedLimiter>,
+    origin: &Origin,
+    item: &InputAuthUser,
+) -> Result<User, Error> {
+    let user_email = utils::normalize_email(&item.email).map_err(ServiceError::ValidationError)?;
+    let (burst, per_minute) = vars::email_rate_limit();
+    throttle(
+        limiter,
+        format!("email:{}", user_email),
+        Rule::per_minute(burst, per_minute),
+    )
+    .await?;
+
     let db_clone = db.clone();
-    let user = web::block(move || db_get_user_by_email(db_clone, &user_email))
+    let user = match logging::db_block("db_get_user_by_email", move || {
+        db_get_user_by_email(db_clone, &user_email)
+    })
+    .await
+    {
+        Ok(user) => user,
+        Err(_) => {
+            // Checked anyway, so the response doesn't come quicker for emails without an account
+            let password = item.password.clone();
+            let _ = web::block(move || utils::verify(utils::dummy_hash()?, &password)).await;
+            metrics::auth_failure("password");
+            return Err(ServiceError::AuthenticationError(String::from(
+                "Err during authentication",
+            ))
+            .into());
+        }
+    };
+
+    let user_id = user.id;
+    let db_clone = db.clone();
+    let locked_for = logging::db_block("db_get_lockout", move || db_get_lockout(db_clone, user_id))
         .await
-        .map_err(|_| HttpResponse::InternalServerError())?;
+        .map_err(ServiceError::from)?;
+    if let Some(seconds) = locked_for {
+        metrics::auth_failure("locked");
+        return Err(ServiceError::TooManyRequests(seconds).into());
+    }
 
-    if verify(&user.pw_hash, &item.password)? {
-        Ok(web::block(move || db_add_api_token(db, user.id))
-            .await
-            .map(|t| HttpResponse::Created().json(t))
-            .map_err(|_| HttpResponse::InternalServerError())?)
+    if !verify_password(user.pw_hash.clone(), item.password.clone()).await? {
+        record_login_failure(db.clone(), user_id).await?;
+        audit::record(db, origin.clone(), Event::by_user(audit::LOGIN_FAILED, user_id)).await;
+        metrics::auth_failure("password");
+        return Err(
+            ServiceError::AuthenticationError(String::from("Err during authentication")).into(),
+        );
+    }
+    // Only told after the password, so this doesn't reveal which accounts are disabled
+    if user.disabled {
+        return Err(ServiceError::Forbidden(String::from("Account is disabled")).into());
+    }
+    if let Err(e) = check_second_factor(db.clone(), user_id, item.totp_code.clone()).await {
+        // Wrong codes count towards the lockout too, or anyone with the password could try
+        // them all. A missing code doesn't, clients learn that one is needed by trying without.
+        if item.totp_code.is_some() {
+            record_login_failure(db.clone(), user_id).await?;
+            audit::record(
+                db,
+                origin.clone(),
+                Event::by_user(audit::LOGIN_FAILED, user_id)
+                    .with_details(serde_json::json!({ "second_factor": true })),
+            )
+            .await;
+        }
+        return Err(e);
+    }
+    // Now that we know the password, bring its hash up to the configured algorithm and cost
+    if needs_rehash(&user.pw_hash) {
+        let hashed_password = hash_password_off_thread(item.password.clone()).await?;
+        let db_clone = db.clone();
+        logging::db_block("db_update_password_hash", move || {
+            db_update_password_hash(db_clone, user_id, &hashed_password)
+        })
+        .await
+        .map_err(ServiceError::from)?;
+    }
+    logging::db_block("db_clear_login_failures", move || db_clear_login_failures(db, user_id))
+        .await
+        .map_err(ServiceError::from)?;
+    Ok(user)
+}
+
+// Handler for POST /auth/refresh
+pub async fn refresh_access_token(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    item: web::Json<InputRefreshToken>,
+) -> Result<HttpResponse, Error> {
+    let presented = item.into_inner().refresh_token;
+    let db_clone = db.clone();
+    let rotated = logging::db_block("db_rotate_refresh_token", move || {
+        db_rotate_refresh_token(db_clone, &presented)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    match rotated {
+        Rotation::Rotated(user_id, new_refresh_token) => {
+            Ok(HttpResponse::Ok().json(token_pair(user_id, new_refresh_token)?))
+        }
+        Rotation::Reused(user_id) => {
+            audit::record(
+                db,
+                Origin::of(&req),
+                Event::by_user(audit::REFRESH_TOKEN_REUSE, user_id),
+            )
+            .await;
+            metrics::auth_failure("refresh_token");
+            Err(ServiceError::AuthenticationError(String::from("Invalid refresh token")).into())
+        }
+        Rotation::Invalid => {
+            metrics::auth_failure("refresh_token");
+            Err(ServiceError::AuthenticationError(String::from("Invalid refresh token")).into())
+        }
+    }
+}
+
+// Handler for POST /auth/logout
+pub async fn logout(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    item: web::Json<InputRefreshToken>,
+) -> Result<HttpResponse, Error> {
+    let presented = item.into_inner().refresh_token;
+    let db_clone = db.clone();
+    let revoked_for = logging::db_block("db_revoke_refresh_token_family", move || {
+        db_revoke_refresh_token_family(db_clone, &presented)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    if let Some(user_id) = revoked_for {
+        audit::record(db, Origin::of(&req), Event::by_user(audit::LOGOUT, user_id)).await;
+    }
+    Ok(HttpResponse::Ok().json("Logged out"))
+}
+
+async fn record_login_failure(db: web::Data<Pool>, user_id: i32) -> Result<(), ServiceError> {
+    logging::db_block("db_record_login_failure", move || {
+        db_record_login_failure(db, user_id)
+    })
+    .await
+    .map_err(ServiceError::from)
+}
+
+// Hashing a password takes tens of milliseconds and MiBs of memory, so it runs on a blocking
+// thread instead of stalling every other request of the worker
+async fn verify_password(pw_hash: String, password: String) -> Result<bool, ServiceError> {
+    web::block(move || verify(&pw_hash, &password))
+        .await
+        .map_err(ServiceError::from)
+}
+
+async fn hash_password_off_thread(password: String) -> Result<String, ServiceError> {
+    web::block(move || utils::hash_password(&password))
+        .await
+        .map_err(ServiceError::from)
+}
+
+// Users with TOTP enabled have to present a code on top of their password
+async fn check_second_factor(
+    db: web::Data<Pool>,
+    user_id: i32,
+    code: Option<String>,
+) -> Result<(), Error> {
+    let passed = logging::db_block("db_check_second_factor", move || {
+        db_check_second_factor(db, user_id, code.as_deref())
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    if passed {
+        Ok(())
     } else {
-        Err(ServiceError::AuthenticationError(String::from("Err during authentication")).into())
+        metrics::auth_failure("totp");
+        Err(ServiceError::AuthenticationError(String::from("Valid TOTP code required")).into())
+    }
+}
+
+// Handler for POST /v1/user/totp/enroll
+pub async fn enroll_totp(db: web::Data<Pool>, auth: BearerAuth) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    let secret = totp::generate_secret();
+    let provisioning_uri = totp::provisioning_uri(&secret, &user.email, &vars::totp_issuer());
+    let enrolled = logging::db_block("db_add_totp_secret", move || {
+        db_add_totp_secret(db, user.id, &secret).map(|added| added.map(|_| secret))
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    match enrolled {
+        Some(secret) => Ok(HttpResponse::Created().json(TotpEnrollment {
+            secret,
+            provisioning_uri,
+        })),
+        None => Err(ServiceError::BadRequest(String::from("TOTP is already enabled")).into()),
     }
 }
 
+// Handler for POST /v1/user/totp/confirm
+pub async fn confirm_totp(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    item: web::Json<InputTotpCode>,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    let user_id = user.id;
+    let db_clone = db.clone();
+    let codes = logging::db_block("db_enable_totp", move || {
+        db_enable_totp(db_clone, user_id, &item.code)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    match codes {
+        Some(recovery_codes) => {
+            audit::record(db, Origin::of(&req), Event::by_user(audit::TOTP_ENABLE, user_id)).await;
+            Ok(HttpResponse::Ok().json(TotpRecoveryCodes { recovery_codes }))
+        }
+        None => Err(ServiceError::BadRequest(String::from("Invalid TOTP code")).into()),
+    }
+}
+
+// Handler for POST /v1/user/totp/disable
+pub async fn disable_totp(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    item: web::Json<InputTotpDisable>,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    if !verify_password(user.pw_hash.clone(), item.password.clone()).await? {
+        return Err(ServiceError::AuthenticationError(String::from("Wrong password")).into());
+    }
+    let item = item.into_inner();
+    check_second_factor(db.clone(), user.id, Some(item.code)).await?;
+    let user_id = user.id;
+    let db_clone = db.clone();
+    logging::db_block("db_delete_totp", move || db_delete_totp(db_clone, user_id))
+        .await
+        .map_err(ServiceError::from)?;
+    audit::record(db, Origin::of(&req), Event::by_user(audit::TOTP_DISABLE, user_id)).await;
+    Ok(HttpResponse::Ok().json("Disabled TOTP"))
+}
+
+fn token_pair(user_id: i32, new_refresh_token: String) -> Result<TokenPair, ServiceError> {
+    Ok(TokenPair {
+        access_token: create_access_token(user_id)?,
+        token_type: String::from("Bearer"),
+        expires_in: vars::access_token_ttl(),
+        refresh_token: new_refresh_token,
+    })
+}
+
 //Handler for GET /token/revoke
 pub async fn revoke_api_token(
     db: web::Data<Pool>,
+    req: HttpRequest,
     auth: BearerAuth,
 ) -> Result<HttpResponse, Error> {
-    Ok(web::block(move || db_update_api_token(db, auth))
+    if is_jwt(auth.token()) {
+        return Err(ServiceError::BadRequest(String::from(
+            "Access tokens expire on their own, use /auth/logout to revoke the refresh token",
+        ))
+        .into());
+    }
+    let user = authenticated_user(db.clone(), auth.clone()).await?;
+    let db_clone = db.clone();
+    logging::db_block("db_update_api_token", move || db_update_api_token(db_clone, auth))
         .await
-        .map(|_| HttpResponse::Created().json("Revoked the access with the API key"))
-        .map_err(|_| HttpResponse::InternalServerError())?)
+        .map_err(ServiceError::from)?;
+    audit::record(db, Origin::of(&req), Event::by_user(audit::TOKEN_REVOKE, user.id)).await;
+    Ok(HttpResponse::Created().json("Revoked the access with the API key"))
 }
 
 // Handler for GET /trove
@@ -90,14 +513,13 @@ pub async fn get_trove_by_profile(
     db: web::Data<Pool>,
     auth: BearerAuth,
 ) -> Result<HttpResponse, Error> {
-    // Can unwrap here, since auth middle wear already checks if a user for exists for a given token
-    let user = db_get_user_by_api_token(db.clone(), auth).unwrap();
-    Ok(
-        web::block(move || db_get_latest_trove_by_user_id(db, user.id))
-            .await
-            .map(|t| HttpResponse::Ok().json(decode_text(t.unwrap_or_default().trove_text)))
-            .map_err(|_| HttpResponse::InternalServerError())?,
-    )
+    let user = authenticated_user(db.clone(), auth).await?;
+    let latest = logging::db_block("db_get_latest_trove_by_user_id", move || {
+        db_get_latest_trove_by_user_id(db, user.id)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    Ok(HttpResponse::Ok().json(decode_text(latest.unwrap_or_default().trove_text)?))
 }
 
 // Handler for PUT /trove
@@ -105,14 +527,79 @@ pub async fn get_trove_by_profile(
 // Handler for GET /users
 pub async fn save_trove_by_token(
     db: web::Data<Pool>,
+    req: HttpRequest,
     auth: BearerAuth,
-    trove_data: web::Bytes,
+    mut payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
-    let user = db_get_user_by_api_token(db.clone(), auth).unwrap();
-    Ok(web::block(move || db_add_trove_text(db, user.id, str::from_utf8(&trove_data).unwrap()))
+    let user = authenticated_user(db.clone(), auth).await?;
+    if vars::verify_email() && !user.verified {
+        return Err(ServiceError::Forbidden(String::from("Email address is not verified")).into());
+    }
+    let limits = quota::limits_for(&user);
+
+    // Read the body ourselves, so an oversized upload is cut off as soon as it crosses the limit
+    let mut trove_data = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if trove_data.len() + chunk.len() > limits.max_trove_bytes {
+            return Err(ServiceError::PayloadTooLarge(format!(
+                "Trove exceeds the limit of {} bytes",
+                limits.max_trove_bytes
+            ))
+            .into());
+        }
+        trove_data.extend_from_slice(&chunk);
+    }
+    let text = str::from_utf8(&trove_data)
+        .map_err(|_| ServiceError::BadRequest(String::from("Trove is not valid UTF-8")))?
+        .to_string();
+
+    quota::check_trove(&limits, &text)?;
+
+    let user_id = user.id;
+    let bytes = text.len();
+    let db_clone = db.clone();
+    let saved = logging::db_block("db_add_trove_text", move || {
+        db_add_trove_text(db_clone, user_id, &text, limits.max_revisions)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    metrics::trove_uploaded(bytes);
+    let event = Event::by_user(audit::TROVE_WRITE, user_id)
+        .with_details(serde_json::json!({ "trove_id": saved.id, "bytes": bytes }));
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Created().json("Saved trove!"))
+}
+
+// Handler for GET /v1/usage
+pub async fn get_usage(db: web::Data<Pool>, auth: BearerAuth) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    Ok(HttpResponse::Ok().json(usage_for(db, &user).await?))
+}
+
+// Current usage of a user and the limits of their tier
+pub async fn usage_for(db: web::Data<Pool>, user: &User) -> Result<Usage, Error> {
+    let user_id = user.id;
+    let db_clone = db.clone();
+    let revisions = logging::db_block("db_count_troves", move || db_count_troves(db_clone, user_id))
         .await
-        .map(|_| HttpResponse::Created().json("Saved trove!"))
-        .map_err(|_| HttpResponse::InternalServerError())?)
+        .map_err(ServiceError::from)?;
+    let latest = logging::db_block("db_get_latest_trove_by_user_id", move || {
+        db_get_latest_trove_by_user_id(db, user_id)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let latest_text = match latest {
+        Some(t) => decode_text(t.trove_text)?,
+        None => String::new(),
+    };
+    Ok(Usage {
+        tier: quota::tier(user).to_string(),
+        limits: quota::limits_for(user),
+        trove_bytes: latest_text.len(),
+        revisions,
+        commands: quota::count_commands(&latest_text).unwrap_or(0),
+    })
 }
 
 #[allow(dead_code)]
@@ -122,72 +609,499 @@ pub async fn get_user_by_id(
     user_id: web::Path<i32>,
 ) -> Result<HttpResponse, Error> {
     Ok(
-        web::block(move || db_get_user_by_id(db, user_id.into_inner()))
+        logging::db_block("db_get_user_by_id", move || db_get_user_by_id(db, user_id.into_inner()))
             .await
-            .map(|user| HttpResponse::Ok().json(user))
-            .map_err(|_| HttpResponse::InternalServerError())?,
+            .map(|user| HttpResponse::Ok().json(UserProfile::from(&user)))
+            .map_err(ServiceError::from)?,
     )
 }
 
-#[allow(dead_code)]
+// Handler for GET /v1/user
 pub async fn get_user_by_token(
     auth: BearerAuth,
     db: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    Ok(web::block(move || db_get_user_by_api_token(db, auth))
-        .await
-        .map(|user| HttpResponse::Ok().json(user))
-        .map_err(|_| HttpResponse::InternalServerError())?)
+    let user = authenticated_user(db, auth).await?;
+    Ok(HttpResponse::Ok().json(UserProfile::from(&user)))
+}
+
+// Handler for PATCH /v1/user
+pub async fn update_preferences(
+    db: web::Data<Pool>,
+    auth: BearerAuth,
+    item: web::Json<InputPreferences>,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    let change = check_preferences(item.into_inner())?;
+    Ok(logging::db_block("db_update_preferences", move || {
+        db_update_preferences(db, user.id, change)
+    })
+    .await
+    .map(|user| HttpResponse::Ok().json(UserProfile::from(&user)))
+    .map_err(ServiceError::from)?)
 }
 
-// Handler for DELETE /users/{id}
+fn check_preferences(item: InputPreferences) -> Result<PreferencesChange, ServiceError> {
+    // An empty display name is the same as none
+    let name = item
+        .display_name
+        .map(|n| n.map(|n| n.trim().to_string()).filter(|n| !n.is_empty()));
+    if let Some(Some(n)) = &name {
+        if n.chars().count() > MAX_DISPLAY_NAME_LEN {
+            return Err(ServiceError::ValidationError(format!(
+                "Display name must be at most {} characters long",
+                MAX_DISPLAY_NAME_LEN
+            )));
+        }
+    }
+    if let Some(tz) = &item.timezone {
+        if !utils::is_valid_timezone(tz) {
+            return Err(ServiceError::ValidationError(format!("Unknown timezone {}", tz)));
+        }
+    }
+    if let Some(strategy) = &item.sync_conflict_strategy {
+        if !SYNC_CONFLICT_STRATEGIES.contains(&strategy.as_str()) {
+            return Err(ServiceError::ValidationError(format!(
+                "Sync conflict strategy must be one of {}",
+                SYNC_CONFLICT_STRATEGIES.join(", ")
+            )));
+        }
+    }
+    Ok(PreferencesChange {
+        display_name: name,
+        default_trove: item.default_trove,
+        timezone: item.timezone,
+        sync_conflict_strategy: item.sync_conflict_strategy,
+    })
+}
+
+// Handler for GET /v1/user/export
+pub async fn export_user_data(db: web::Data<Pool>, auth: BearerAuth) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    let filename = format!("trove-export-{}.tar", user.id);
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .streaming(export::stream_export(db, user)))
+}
+
+// Handler for DELETE /v1/user
+// Schedules the account for deletion, it is purged once the grace period ran out
 pub async fn delete_user_by_token(
     db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    item: web::Json<InputAccountDeletion>,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    let password = match item.into_inner().password {
+        Some(password) => password,
+        None => {
+            send_deletion_confirmation(db, mailer, user).await?;
+            return Ok(HttpResponse::Accepted().json("Sent a link to confirm the deletion"));
+        }
+    };
+    if !verify_password(user.pw_hash.clone(), password).await? {
+        return Err(ServiceError::AuthenticationError(String::from("Wrong password")).into());
+    }
+    let scheduled_at = chrono::Local::now().naive_local();
+    let user_id = user.id;
+    let db_clone = db.clone();
+    logging::db_block("db_set_deleted_at", move || {
+        db_set_deleted_at(db_clone, user_id, Some(scheduled_at))
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    audit::record(db, Origin::of(&req), Event::by_user(audit::ACCOUNT_DELETE, user_id)).await;
+    Ok(HttpResponse::Accepted().json(AccountDeletion {
+        deleted_at: scheduled_at,
+        purge_at: deletion::purge_date(scheduled_at),
+    }))
+}
+
+async fn send_deletion_confirmation(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    user: User,
+) -> Result<(), ServiceError> {
+    let ttl = vars::verification_token_ttl();
+    let raw_token = logging::db_block("db_add_verification_token", move || {
+        db_add_verification_token(db, user.id, DELETE_ACCOUNT, ttl, None)
+    })
+    .await?;
+    let body = format!(
+        "Someone asked to delete your trove account. To confirm, open {}/account/delete/{}\n\
+         The link is valid for {} minutes. If this wasn't you, ignore this mail.",
+        vars::public_url(),
+        raw_token,
+        ttl / 60
+    );
+    web::block(move || mailer.send(&user.email, "Confirm deleting your trove account", &body))
+        .await?;
+    Ok(())
+}
+
+// Handler for GET /account/delete/{token}
+// Schedules the deletion asked for without a password, like DELETE /v1/user does with one
+pub async fn confirm_account_deletion(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    raw_token: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let raw_token = raw_token.into_inner();
+    let scheduled_at = chrono::Local::now().naive_local();
+    let db_clone = db.clone();
+    let deleted = logging::db_block("db_confirm_deletion", move || {
+        db_confirm_deletion(db_clone, &raw_token, scheduled_at)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let user_id = deleted.ok_or_else(|| {
+        ServiceError::BadRequest(String::from("Invalid or expired confirmation link"))
+    })?;
+    let event = Event::by_user(audit::ACCOUNT_DELETE, user_id)
+        .with_details(serde_json::json!({ "confirmed_by": "email" }));
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Accepted().json(AccountDeletion {
+        deleted_at: scheduled_at,
+        purge_at: deletion::purge_date(scheduled_at),
+    }))
+}
+
+// Handler for POST /v1/user/restore
+// Cancels a pending deletion, the only /v1 request accepted for an account scheduled for deletion
+pub async fn restore_user(
+    db: web::Data<Pool>,
+    req: HttpRequest,
     auth: BearerAuth,
 ) -> Result<HttpResponse, Error> {
-    let user = db_get_user_by_api_token(db.clone(), auth).unwrap();
-    Ok(web::block(move || delete_single_user(db, user.id))
+    let user = authenticated_user(db.clone(), auth).await?;
+    if user.deleted_at.is_none() {
+        return Err(ServiceError::Conflict(String::from("Account is not scheduled for deletion")).into());
+    }
+    let user_id = user.id;
+    let db_clone = db.clone();
+    logging::db_block("db_set_deleted_at", move || db_set_deleted_at(db_clone, user_id, None))
         .await
-        .map(|_| HttpResponse::Ok().json("Deleted user"))
-        .map_err(|_| HttpResponse::InternalServerError())?)
+        .map_err(ServiceError::from)?;
+    audit::record(db, Origin::of(&req), Event::by_user(audit::ACCOUNT_RESTORE, user_id)).await;
+    Ok(HttpResponse::Ok().json("Restored user"))
 }
 
 // Handler for POST /register
 pub async fn register_user(
     db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    limiter: web::Data<SharedLimiter>,
+    req: HttpRequest,
     item: web::Json<InputUser>,
 ) -> Result<HttpResponse, Error> {
-    let user_email = item.email.clone();
+    let user_email = utils::normalize_email(&item.email).map_err(ServiceError::ValidationError)?;
+    let (burst, per_minute) = vars::email_rate_limit();
+    throttle(
+        limiter,
+        format!("email:{}", user_email),
+        Rule::per_minute(burst, per_minute),
+    )
+    .await?;
+    utils::check_password_policy(&item.password, &user_email)
+        .map_err(ServiceError::ValidationError)?;
+    // The unique index decides, two registrations racing for one email can't both succeed
     let db_clone = db.clone();
-    let is_unique = web::block(move || db_count_user_email(db_clone, &user_email))
-        .await
-        .map(|c| c == 0)
-        .map_err(|_| HttpResponse::InternalServerError())?;
-    if is_unique {
-        Ok(web::block(move || add_single_user(db, item))
-            .await
-            .map(|user| HttpResponse::Created().json(user))
-            .map_err(|_| HttpResponse::InternalServerError())?)
+    let user = logging::db_block("add_single_user", move || {
+        add_single_user(db_clone, &user_email, &item.password)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    audit::record(db.clone(), Origin::of(&req), Event::by_user(audit::REGISTER, user.id)).await;
+    // Without VERIFY_USER the account works right away, so a failed mail only needs a resend
+    if let Err(e) = send_verification_email(db, mailer, user.id, user.email.clone()).await {
+        if vars::verify_email() {
+            return Err(e);
+        }
+        tracing::warn!(error = ?e, "Failed to send verification email");
+    }
+    Ok(HttpResponse::Created().json(UserProfile::from(&user)))
+}
+
+// Handler for GET /verify/{token}
+pub async fn confirm_email(
+    db: web::Data<Pool>,
+    raw_token: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let raw_token = raw_token.into_inner();
+    let confirmed = logging::db_block("db_verify_user_email", move || {
+        db_verify_user_email(db, &raw_token)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    if confirmed {
+        Ok(HttpResponse::Ok().json("Email verified"))
     } else {
-        Err(ServiceError::AuthenticationError(String::from("Email already in use")).into())
+        Err(ServiceError::BadRequest(String::from("Invalid or expired verification link")).into())
+    }
+}
+
+// Handler for POST /v1/verify/resend
+pub async fn resend_verification_email(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    auth: BearerAuth,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    if user.verified {
+        return Err(ServiceError::BadRequest(String::from("Email already verified")).into());
+    }
+    send_verification_email(db, mailer, user.id, user.email).await?;
+    Ok(HttpResponse::Ok().json("Sent verification email"))
+}
+
+// Handler for POST /v1/user/password
+pub async fn change_password(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    auth: BearerAuth,
+    item: web::Json<InputPasswordChange>,
+) -> Result<HttpResponse, Error> {
+    let current_token = if is_jwt(auth.token()) {
+        None
+    } else {
+        Some(decode_token(auth.clone())?)
+    };
+    let user = authenticated_user(db.clone(), auth).await?;
+    if !verify_password(user.pw_hash.clone(), item.old_password.clone()).await? {
+        return Err(ServiceError::AuthenticationError(String::from("Wrong password")).into());
+    }
+    utils::check_password_policy(&item.new_password, &user.email).map_err(ServiceError::ValidationError)?;
+    let hashed_password = hash_password_off_thread(item.new_password.clone()).await?;
+    let revoke_other_tokens = item.revoke_other_tokens;
+    let user_id = user.id;
+    let db_clone = db.clone();
+    logging::db_block("db_set_password", move || {
+        db_set_password(
+            db_clone,
+            user_id,
+            &hashed_password,
+            revoke_other_tokens,
+            current_token.as_deref(),
+        )
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let event = Event::by_user(audit::PASSWORD_CHANGE, user_id)
+        .with_details(serde_json::json!({ "revoked_other_tokens": revoke_other_tokens }));
+    audit::record(db, Origin::of(&req), event).await;
+    Ok(HttpResponse::Ok().json("Changed password"))
+}
+
+// Handler for POST /v1/user/email
+// The address only changes once the link sent to the new address is opened
+pub async fn request_email_change(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    auth: BearerAuth,
+    item: web::Json<InputEmailChange>,
+) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db.clone(), auth).await?;
+    if !verify_password(user.pw_hash.clone(), item.password.clone()).await? {
+        return Err(ServiceError::AuthenticationError(String::from("Wrong password")).into());
+    }
+    let new_address =
+        utils::normalize_email(&item.new_email).map_err(ServiceError::ValidationError)?;
+    if new_address == user.email {
+        return Err(ServiceError::BadRequest(String::from("This is already your email")).into());
+    }
+    let db_clone = db.clone();
+    let address = new_address.clone();
+    let taken = logging::db_block("db_count_user_email", move || {
+        db_count_user_email(db_clone, &address)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    if taken > 0 {
+        return Err(ServiceError::Conflict(String::from("Email already in use")).into());
+    }
+
+    let ttl = vars::verification_token_ttl();
+    let address = new_address.clone();
+    let raw_token = logging::db_block("db_add_verification_token", move || {
+        db_add_verification_token(db, user.id, CHANGE_EMAIL, ttl, Some(&address))
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let body = format!(
+        "Confirm {} as the new email of your trove account by opening {}/email/confirm/{}",
+        new_address,
+        vars::public_url(),
+        raw_token
+    );
+    web::block(move || mailer.send(&new_address, "Confirm your new email", &body))
+        .await
+        .map_err(ServiceError::from)?;
+    Ok(HttpResponse::Accepted().json("Sent confirmation to the new email"))
+}
+
+// Handler for GET /email/confirm/{token}
+pub async fn confirm_email_change(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    req: HttpRequest,
+    raw_token: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let raw_token = raw_token.into_inner();
+    let db_clone = db.clone();
+    let changed = logging::db_block("db_change_email", move || {
+        db_change_email(db_clone, &raw_token)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let (user_id, old_address, new_address) = changed.ok_or_else(|| {
+        ServiceError::BadRequest(String::from("Invalid or expired confirmation link"))
+    })?;
+    audit::record(db, Origin::of(&req), Event::by_user(audit::EMAIL_CHANGE, user_id)).await;
+    let body = format!(
+        "The email of your trove account was changed to {}. If this was not you, reset your password and contact support.",
+        new_address
+    );
+    web::block(move || mailer.send(&old_address, "Your trove email was changed", &body))
+        .await
+        .map_err(ServiceError::from)?;
+    Ok(HttpResponse::Ok().json("Changed email"))
+}
+
+// Handler for POST /password/forgot
+pub async fn forgot_password(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    limiter: web::Data<SharedLimiter>,
+    item: web::Json<InputForgotPassword>,
+) -> Result<HttpResponse, Error> {
+    let user_email = utils::normalize_email(&item.email).map_err(ServiceError::ValidationError)?;
+    let (burst, per_minute) = vars::email_rate_limit();
+    throttle(
+        limiter,
+        format!("email:{}", user_email),
+        Rule::per_minute(burst, per_minute),
+    )
+    .await?;
+    let db_clone = db.clone();
+    let user = logging::db_block("db_get_user_by_email", move || {
+        db_get_user_by_email(db_clone, &user_email)
+    })
+    .await;
+    // Answer the same either way, so this can't be used to find out who has an account.
+    // That includes failing to send the mail, which only happens for existing accounts.
+    if let Ok(user) = user {
+        if let Err(e) = send_password_reset(db, mailer, user).await {
+            tracing::error!(error = ?e, "Could not send password reset mail");
+        }
     }
+    Ok(HttpResponse::Ok().json("If the account exists, a reset token has been sent"))
+}
+
+async fn send_password_reset(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    user: User,
+) -> Result<(), ServiceError> {
+    let ttl = vars::password_reset_token_ttl();
+    let raw_token = logging::db_block("db_add_verification_token", move || {
+        db_add_verification_token(db, user.id, PASSWORD_RESET, ttl, None)
+    })
+    .await?;
+    let body = format!(
+        "Someone asked to reset the password of your trove account.\n\
+         To choose a new one, POST {{\"token\": \"{}\", \"new_password\": ...}} to {}/password/reset\n\
+         The token is valid for {} minutes. If this wasn't you, ignore this mail.",
+        raw_token,
+        vars::public_url(),
+        ttl / 60
+    );
+    web::block(move || mailer.send(&user.email, "Reset your trove password", &body)).await?;
+    Ok(())
+}
+
+// Handler for POST /password/reset
+pub async fn reset_password(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    item: web::Json<InputPasswordReset>,
+) -> Result<HttpResponse, Error> {
+    let item = item.into_inner();
+    utils::check_password_policy(&item.new_password, "").map_err(ServiceError::ValidationError)?;
+    let hashed_password = hash_password_off_thread(item.new_password.clone()).await?;
+    let db_clone = db.clone();
+    let reset_for = logging::db_block("db_reset_password", move || {
+        db_reset_password(db_clone, &item.token, &hashed_password)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    match reset_for {
+        Some(user_id) => {
+            audit::record(
+                db,
+                Origin::of(&req),
+                Event::by_user(audit::PASSWORD_RESET, user_id),
+            )
+            .await;
+            Ok(HttpResponse::Ok().json("Changed password"))
+        }
+        None => {
+            Err(ServiceError::BadRequest(String::from("Invalid or expired reset token")).into())
+        }
+    }
+}
+
+async fn send_verification_email(
+    db: web::Data<Pool>,
+    mailer: web::Data<SharedMailer>,
+    user_id: i32,
+    user_email: String,
+) -> Result<(), Error> {
+    let ttl = vars::verification_token_ttl();
+    let raw_token = logging::db_block("db_add_verification_token", move || {
+        db_add_verification_token(db, user_id, VERIFY_EMAIL, ttl, None)
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    let body = format!(
+        "Confirm your trove account by opening {}/verify/{}",
+        vars::public_url(),
+        raw_token
+    );
+    web::block(move || mailer.send(&user_email, "Verify your trove account", &body))
+        .await
+        .map_err(ServiceError::from)?;
+    Ok(())
 }
 
-fn db_get_user_by_id(pool: web::Data<Pool>, user_id: i32) -> Result<User, diesel::result::Error> {
-    let conn = pool.get().unwrap();
-    users.find(user_id).get_result::<User>(&conn)
+pub fn db_get_user_by_id(pool: web::Data<Pool>, user_id: i32) -> Result<User, ServiceError> {
+    let conn = pool.get()?;
+    Ok(users.find(user_id).get_result::<User>(&conn)?)
 }
 
-fn db_get_latest_trove_by_user_id(pool: web::Data<Pool>, user_id: i32) -> Result<Option<Trove>, diesel::result::Error> {
-    let conn = pool.get().unwrap();
-    trove.filter(schema::trove::user_id_fk.eq(user_id)).order_by(schema::trove::id.desc()).first(&conn).optional()
+fn db_get_latest_trove_by_user_id(pool: web::Data<Pool>, user_id: i32) -> Result<Option<Trove>, ServiceError> {
+    let conn = pool.get()?;
+    Ok(trove.filter(schema::trove::user_id_fk.eq(user_id)).order_by(schema::trove::id.desc()).first(&conn).optional()?)
 }
 
-fn db_get_user_by_email(
+fn db_count_troves(pool: web::Data<Pool>, user_id: i32) -> Result<i64, ServiceError> {
+    let conn = pool.get()?;
+    Ok(trove
+        .filter(schema::trove::user_id_fk.eq(user_id))
+        .count()
+        .get_result(&conn)?)
+}
+
+pub fn db_get_user_by_email(
     pool: web::Data<Pool>,
     user_email: &str,
-) -> Result<User, diesel::result::Error> {
-    let conn = pool.get().unwrap();
+) -> Result<User, ServiceError> {
+    let conn = pool.get()?;
     // Distinct, since user email cant be duplicate. Just to make sure to only return one user
     let user = users
         .filter(email.eq(user_email))
@@ -199,9 +1113,9 @@ fn db_get_user_by_email(
 pub fn db_get_user_by_api_token(
     pool: web::Data<Pool>,
     requested_api_token: BearerAuth,
-) -> Result<User, diesel::result::Error> {
-    let conn = pool.get().unwrap();
-    let s = decode_token(requested_api_token);
+) -> Result<User, ServiceError> {
+    let conn = pool.get()?;
+    let s = decode_token(requested_api_token)?;
     let user_api_token: APIToken = api_token.filter(token.eq(s)).distinct().get_result(&conn)?;
     db_get_user_by_id(pool, user_api_token.user_id)
 }
@@ -209,16 +1123,15 @@ pub fn db_get_user_by_api_token(
 pub fn db_is_token_revoked(
     pool: web::Data<Pool>,
     requested_api_token: BearerAuth,
-) -> Result<bool, diesel::result::Error> {
+) -> Result<bool, ServiceError> {
     // Should be a inner join on api token with users on `user_id_fk`, but have not read up on diesel enough yet
-    let conn = pool.get().unwrap();
-    let s = decode_token(requested_api_token);
+    let conn = pool.get()?;
+    let s = decode_token(requested_api_token)?;
     let user_api_token: Option<APIToken> = api_token
         .filter(token.eq(s).and(revoked.eq(true)))
         .distinct()
         .get_result(&conn)
-        .optional()
-        .unwrap();
+        .optional()?;
     if user_api_token.is_some() {
         return Ok(true);
     }
@@ -228,29 +1141,86 @@ pub fn db_is_token_revoked(
 pub fn db_update_api_token(
     pool: web::Data<Pool>,
     requested_api_token: BearerAuth,
-) -> Result<bool, diesel::result::Error> {
+) -> Result<bool, ServiceError> {
     // Should be a inner join on api token with users on `user_id_fk`, but have not read up on diesel enough yet
-    let conn = pool.get().unwrap();
-    let s = utils::decode_token(requested_api_token);
+    let conn = pool.get()?;
+    let s = utils::decode_token(requested_api_token)?;
     let token_option: Option<APIToken> = api_token
         .filter(token.eq(s))
         .distinct()
         .get_result(&conn)
-        .optional()
-        .unwrap();
+        .optional()?;
     if let Some(token_id) = token_option {
-        let _ = diesel::update(api_token.filter(schema::api_token::dsl::id.eq(token_id.id)))
+        diesel::update(api_token.filter(schema::api_token::dsl::id.eq(token_id.id)))
             .set(schema::api_token::revoked.eq(true))
-            .execute(&conn);
+            .execute(&conn)?;
     };
     Ok(true)
 }
 
+// Resolves an identity provider login to its account, by the provider's subject once linked.
+// The first login links the account with the same email, see `oidc::account_to_link`, ending its
+// sessions and local password, or creates one. The provider vouches for the email, so a created
+// account starts verified.
+pub fn db_get_or_create_oidc_user(
+    pool: web::Data<Pool>,
+    identity: &OidcIdentity,
+) -> Result<User, ServiceError> {
+    use schema::users::dsl as us;
+    let user_email =
+        utils::normalize_email(&identity.email).map_err(ServiceError::AuthenticationError)?;
+    let conn = pool.get()?;
+    let linked: Option<User> = us::users
+        .filter(us::oidc_subject.eq(&identity.subject))
+        .get_result(&conn)
+        .optional()?;
+    if let Some(user) = linked {
+        return Ok(user);
+    }
+    // Nobody knows this password, the account can only be used through the identity provider
+    let hashed_password = utils::hash_password(&generate_refresh_token())?;
+    conn.transaction(|| {
+        let existing: Option<User> = us::users
+            .filter(us::email.eq(&user_email))
+            .for_update()
+            .get_result(&conn)
+            .optional()?;
+        let user_id = match oidc::account_to_link(existing.as_ref())? {
+            Some(user_id) => {
+                revoke_user_tokens(&conn, user_id, None)?;
+                user_id
+            }
+            None => {
+                let now = chrono::Local::now().naive_local();
+                insert_into(us::users)
+                    .values(&NewUser {
+                        email: &user_email,
+                        pw_hash: &hashed_password,
+                        verified: true,
+                        subscribed: false,
+                        created_at: now,
+                        last_payment: now,
+                        admin: false,
+                    })
+                    .returning(us::id)
+                    .get_result(&conn)
+                    .map_err(email_in_use)?
+            }
+        };
+        Ok(diesel::update(us::users.find(user_id))
+            .set((
+                us::oidc_subject.eq(&identity.subject),
+                us::pw_hash.eq(&hashed_password),
+            ))
+            .get_result(&conn)?)
+    })
+}
+
 fn db_count_user_email(
     pool: web::Data<Pool>,
     user_email: &str,
-) -> Result<usize, diesel::result::Error> {
-    let conn = pool.get().unwrap();
+) -> Result<usize, ServiceError> {
+    let conn = pool.get()?;
     let user_count = users
         .filter(email.eq(user_email))
         .count()
@@ -259,14 +1229,14 @@ fn db_count_user_email(
 }
 
 #[allow(dead_code)]
-fn get_all_users(pool: web::Data<Pool>) -> Result<Vec<User>, diesel::result::Error> {
-    let conn = pool.get().unwrap();
+fn get_all_users(pool: web::Data<Pool>) -> Result<Vec<User>, ServiceError> {
+    let conn = pool.get()?;
     let items = users.load::<User>(&conn)?;
     Ok(items)
 }
 
-fn db_add_api_token(db: web::Data<Pool>, user_id: i32) -> Result<APIToken, diesel::result::Error> {
-    let conn = db.get().unwrap();
+fn db_add_api_token(db: web::Data<Pool>, user_id: i32) -> Result<APIToken, ServiceError> {
+    let conn = db.get()?;
     let new_token = NewToken {
         token: &generate_api_token(),
         user_id_fk: user_id,
@@ -278,39 +1248,649 @@ fn db_add_api_token(db: web::Data<Pool>, user_id: i32) -> Result<APIToken, diese
     Ok(res)
 }
 
+// Stores a new refresh token of the given family and returns the raw token to hand out
+fn db_add_refresh_token(
+    db: web::Data<Pool>,
+    user_id: i32,
+    token_family: &str,
+) -> Result<String, ServiceError> {
+    let conn = db.get()?;
+    Ok(insert_refresh_token(&conn, user_id, token_family)?)
+}
+
+fn insert_refresh_token(
+    conn: &PgConnection,
+    user_id: i32,
+    token_family: &str,
+) -> Result<String, diesel::result::Error> {
+    let raw_token = generate_refresh_token();
+    let now = chrono::Local::now().naive_local();
+    let new_refresh_token = NewRefreshToken {
+        token_hash: &hash_token(&raw_token),
+        family: token_family,
+        user_id_fk: user_id,
+        created_at: now,
+        expires_at: now + chrono::Duration::seconds(vars::refresh_token_ttl()),
+    };
+    insert_into(refresh_token)
+        .values(&new_refresh_token)
+        .execute(conn)?;
+    Ok(raw_token)
+}
+
+enum Rotation {
+    // The user and their new refresh token
+    Rotated(i32, String),
+    // An already used token of this user was presented
+    Reused(i32),
+    Invalid,
+}
+
+// What presenting a stored refresh token leads to
+#[derive(Debug, PartialEq)]
+enum RefreshCheck<'a> {
+    Rotate,
+    // An already used token means it leaked, so every token of its family goes
+    RevokeFamily(&'a str),
+    Reject,
+}
+
+// Reuse is checked first, a leaked token stays a sign of theft after it was revoked or expired
+fn check_refresh_token(stored: &RefreshToken, now: chrono::NaiveDateTime) -> RefreshCheck<'_> {
+    if stored.used {
+        RefreshCheck::RevokeFamily(&stored.family)
+    } else if stored.revoked || stored.expires_at < now {
+        RefreshCheck::Reject
+    } else {
+        RefreshCheck::Rotate
+    }
+}
+
+// Exchanges a refresh token for a new one of the same family.
+// Presenting an already used token means it leaked, so the whole family gets revoked.
+fn db_rotate_refresh_token(
+    db: web::Data<Pool>,
+    raw_token: &str,
+) -> Result<Rotation, ServiceError> {
+    use schema::refresh_token::dsl as rt;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let stored: Option<RefreshToken> = refresh_token
+            .filter(rt::token_hash.eq(hash_token(raw_token)))
+            .for_update()
+            .get_result(&conn)
+            .optional()?;
+        let stored = match stored {
+            Some(t) => t,
+            None => return Ok(Rotation::Invalid),
+        };
+        match check_refresh_token(&stored, chrono::Local::now().naive_local()) {
+            RefreshCheck::RevokeFamily(family) => {
+                diesel::update(refresh_token.filter(rt::family.eq(family)))
+                    .set(rt::revoked.eq(true))
+                    .execute(&conn)?;
+                return Ok(Rotation::Reused(stored.user_id));
+            }
+            RefreshCheck::Reject => return Ok(Rotation::Invalid),
+            RefreshCheck::Rotate => {}
+        }
+        diesel::update(refresh_token.find(stored.id))
+            .set(rt::used.eq(true))
+            .execute(&conn)?;
+        let new_raw_token = insert_refresh_token(&conn, stored.user_id, &stored.family)?;
+        Ok(Rotation::Rotated(stored.user_id, new_raw_token))
+    })
+}
+
+// Returns the user whose tokens were revoked, if the token was known
+fn db_revoke_refresh_token_family(
+    db: web::Data<Pool>,
+    raw_token: &str,
+) -> Result<Option<i32>, ServiceError> {
+    use schema::refresh_token::dsl as rt;
+    let conn = db.get()?;
+    let stored: Option<RefreshToken> = refresh_token
+        .filter(rt::token_hash.eq(hash_token(raw_token)))
+        .get_result(&conn)
+        .optional()?;
+    match stored {
+        Some(t) => {
+            diesel::update(refresh_token.filter(rt::family.eq(t.family)))
+                .set(rt::revoked.eq(true))
+                .execute(&conn)?;
+            Ok(Some(t.user_id))
+        }
+        None => Ok(None),
+    }
+}
+
+// Stores a single use token for the given purpose and returns the raw token to mail out
+pub fn db_add_verification_token(
+    db: web::Data<Pool>,
+    user_id: i32,
+    token_purpose: &str,
+    ttl: i64,
+    new_address: Option<&str>,
+) -> Result<String, ServiceError> {
+    use schema::verification_token::dsl::verification_token;
+    let conn = db.get()?;
+    let raw_token = generate_refresh_token();
+    let now = chrono::Local::now().naive_local();
+    let new_token = NewVerificationToken {
+        token_hash: &hash_token(&raw_token),
+        purpose: token_purpose,
+        user_id_fk: user_id,
+        created_at: now,
+        expires_at: now + chrono::Duration::seconds(ttl),
+        new_email: new_address,
+    };
+    insert_into(verification_token)
+        .values(&new_token)
+        .execute(&conn)?;
+    Ok(raw_token)
+}
+
+// Marks an unused, unexpired token of the given purpose as used and returns it
+pub fn consume_verification_token(
+    conn: &PgConnection,
+    raw_token: &str,
+    token_purpose: &str,
+) -> Result<Option<VerificationToken>, diesel::result::Error> {
+    use schema::verification_token::dsl as vt;
+    let stored: Option<VerificationToken> = vt::verification_token
+        .filter(vt::token_hash.eq(hash_token(raw_token)))
+        .filter(vt::purpose.eq(token_purpose))
+        .filter(vt::used.eq(false))
+        .filter(vt::expires_at.gt(chrono::Local::now().naive_local()))
+        .for_update()
+        .get_result(conn)
+        .optional()?;
+    match stored {
+        Some(t) => {
+            diesel::update(vt::verification_token.find(t.id))
+                .set(vt::used.eq(true))
+                .execute(conn)?;
+            Ok(Some(t))
+        }
+        None => Ok(None),
+    }
+}
+
+fn db_verify_user_email(db: web::Data<Pool>, raw_token: &str) -> Result<bool, ServiceError> {
+    let conn = db.get()?;
+    conn.transaction(|| match consume_verification_token(&conn, raw_token, VERIFY_EMAIL)? {
+        Some(t) => {
+            diesel::update(users.find(t.user_id))
+                .set(verified.eq(true))
+                .execute(&conn)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    })
+}
+
+fn db_update_password_hash(
+    db: web::Data<Pool>,
+    user_id: i32,
+    hashed_password: &str,
+) -> Result<usize, ServiceError> {
+    let conn = db.get()?;
+    Ok(diesel::update(users.find(user_id))
+        .set(pw_hash.eq(hashed_password))
+        .execute(&conn)?)
+}
+
+fn db_set_password(
+    db: web::Data<Pool>,
+    user_id: i32,
+    hashed_password: &str,
+    revoke_other_tokens: bool,
+    current_token: Option<&str>,
+) -> Result<(), ServiceError> {
+    let conn = db.get()?;
+    conn.transaction(|| {
+        diesel::update(users.find(user_id))
+            .set(pw_hash.eq(hashed_password))
+            .execute(&conn)?;
+        if revoke_other_tokens {
+            revoke_user_tokens(&conn, user_id, current_token)?;
+        }
+        Ok(())
+    })
+}
+
+// A reset means the old password may be compromised, so every session of the user ends.
+// Returns whose password was reset, if the token was valid.
+fn db_reset_password(
+    db: web::Data<Pool>,
+    raw_token: &str,
+    hashed_password: &str,
+) -> Result<Option<i32>, ServiceError> {
+    let conn = db.get()?;
+    conn.transaction(|| match consume_verification_token(&conn, raw_token, PASSWORD_RESET)? {
+        Some(t) => {
+            // The link was mailed to the address, so opening it confirms the address too
+            diesel::update(users.find(t.user_id))
+                .set((pw_hash.eq(hashed_password), verified.eq(true)))
+                .execute(&conn)?;
+            revoke_user_tokens(&conn, t.user_id, None)?;
+            Ok(Some(t.user_id))
+        }
+        None => Ok(None),
+    })
+}
+
+// Swaps in the address the token was sent to, returns the user with their old and new address.
+// Uniqueness is checked again, the address may have been taken since the change was requested.
+fn db_change_email(
+    db: web::Data<Pool>,
+    raw_token: &str,
+) -> Result<Option<(i32, String, String)>, ServiceError> {
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let t = match consume_verification_token(&conn, raw_token, CHANGE_EMAIL)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let new_address = t.new_email.ok_or(ServiceError::InternalServerError)?;
+        let taken: i64 = users
+            .filter(email.eq(&new_address))
+            .count()
+            .get_result(&conn)?;
+        if taken > 0 {
+            return Err(ServiceError::Conflict(String::from("Email already in use")));
+        }
+        let old_address: String = users.find(t.user_id).select(email).first(&conn)?;
+        diesel::update(users.find(t.user_id))
+            .set((email.eq(&new_address), verified.eq(true)))
+            .execute(&conn)
+            .map_err(email_in_use)?;
+        Ok(Some((t.user_id, old_address, new_address)))
+    })
+}
+
+// Schedules the deletion a mailed link confirms, returns whose account it is
+fn db_confirm_deletion(
+    db: web::Data<Pool>,
+    raw_token: &str,
+    scheduled_at: chrono::NaiveDateTime,
+) -> Result<Option<i32>, ServiceError> {
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let t = match consume_verification_token(&conn, raw_token, DELETE_ACCOUNT)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        diesel::update(users.find(t.user_id))
+            .set(deleted_at.eq(scheduled_at))
+            .execute(&conn)?;
+        Ok(Some(t.user_id))
+    })
+}
+
+// Revokes all API and refresh tokens of a user, optionally keeping the API token in use
+pub fn revoke_user_tokens(
+    conn: &PgConnection,
+    user_id: i32,
+    keep_token: Option<&str>,
+) -> Result<(), diesel::result::Error> {
+    use schema::api_token::dsl as at;
+    use schema::refresh_token::dsl as rt;
+    let user_api_tokens = at::api_token.filter(at::user_id_fk.eq(user_id));
+    match keep_token {
+        Some(keep) => diesel::update(user_api_tokens.filter(at::token.ne(keep)))
+            .set(at::revoked.eq(true))
+            .execute(conn)?,
+        None => diesel::update(user_api_tokens)
+            .set(at::revoked.eq(true))
+            .execute(conn)?,
+    };
+    diesel::update(refresh_token.filter(rt::user_id_fk.eq(user_id)))
+        .set(rt::revoked.eq(true))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Returns the seconds left if the account is currently locked
+fn db_get_lockout(db: web::Data<Pool>, user_id: i32) -> Result<Option<u64>, ServiceError> {
+    use schema::account_lockout::dsl as al;
+    let conn = db.get()?;
+    let locked_until: Option<Option<chrono::NaiveDateTime>> = al::account_lockout
+        .find(user_id)
+        .select(al::locked_until)
+        .get_result(&conn)
+        .optional()?;
+    let now = chrono::Local::now().naive_local();
+    Ok(locked_until
+        .flatten()
+        .filter(|until| *until > now)
+        .map(|until| (until - now).num_seconds().max(1) as u64))
+}
+
+fn db_record_login_failure(db: web::Data<Pool>, user_id: i32) -> Result<(), ServiceError> {
+    use schema::account_lockout::dsl as al;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let failed: i32 = insert_into(al::account_lockout)
+            .values((al::user_id_fk.eq(user_id), al::failed_attempts.eq(1)))
+            .on_conflict(al::user_id_fk)
+            .do_update()
+            .set(al::failed_attempts.eq(al::failed_attempts + 1))
+            .returning(al::failed_attempts)
+            .get_result(&conn)?;
+        if failed >= vars::lockout_max_failures() {
+            let until =
+                chrono::Local::now().naive_local() + chrono::Duration::seconds(vars::lockout_duration());
+            diesel::update(al::account_lockout.find(user_id))
+                .set((al::failed_attempts.eq(0), al::locked_until.eq(until)))
+                .execute(&conn)?;
+        }
+        Ok(())
+    })
+}
+
+fn db_clear_login_failures(db: web::Data<Pool>, user_id: i32) -> Result<usize, ServiceError> {
+    use schema::account_lockout::dsl as al;
+    let conn = db.get()?;
+    Ok(delete(al::account_lockout.find(user_id)).execute(&conn)?)
+}
+
+// Stores a fresh secret unless TOTP is already enabled, replacing an unconfirmed enrollment
+fn db_add_totp_secret(
+    db: web::Data<Pool>,
+    user_id: i32,
+    totp_secret: &str,
+) -> Result<Option<()>, ServiceError> {
+    use schema::totp_secret::dsl as ts;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let existing: Option<TotpSecret> = ts::totp_secret
+            .find(user_id)
+            .for_update()
+            .get_result(&conn)
+            .optional()?;
+        if existing.map(|t| t.enabled).unwrap_or(false) {
+            return Ok(None);
+        }
+        delete(ts::totp_secret.find(user_id)).execute(&conn)?;
+        insert_into(ts::totp_secret)
+            .values(&NewTotpSecret {
+                user_id_fk: user_id,
+                secret: totp_secret,
+                created_at: chrono::Local::now().naive_local(),
+            })
+            .execute(&conn)?;
+        Ok(Some(()))
+    })
+}
+
+// Enables TOTP once the user proved their app produces valid codes, and hands out recovery codes
+fn db_enable_totp(
+    db: web::Data<Pool>,
+    user_id: i32,
+    code: &str,
+) -> Result<Option<Vec<String>>, ServiceError> {
+    use schema::recovery_code::dsl as rc;
+    use schema::totp_secret::dsl as ts;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let pending: Option<TotpSecret> = ts::totp_secret
+            .find(user_id)
+            .filter(ts::enabled.eq(false))
+            .for_update()
+            .get_result(&conn)
+            .optional()?;
+        let pending = match pending {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let step = match totp::verify_code(&pending.secret, code, chrono::Utc::now().timestamp()) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+        diesel::update(ts::totp_secret.find(user_id))
+            .set((ts::enabled.eq(true), ts::last_used_step.eq(step)))
+            .execute(&conn)?;
+
+        delete(rc::recovery_code.filter(rc::user_id_fk.eq(user_id))).execute(&conn)?;
+        let codes: Vec<String> = (0..10).map(|_| generate_recovery_code()).collect();
+        let new_codes: Vec<NewRecoveryCode> = codes
+            .iter()
+            .map(|c| NewRecoveryCode {
+                user_id_fk: user_id,
+                code_hash: hash_token(c),
+            })
+            .collect();
+        insert_into(rc::recovery_code)
+            .values(&new_codes)
+            .execute(&conn)?;
+        Ok(Some(codes))
+    })
+}
+
+fn db_delete_totp(db: web::Data<Pool>, user_id: i32) -> Result<(), ServiceError> {
+    use schema::recovery_code::dsl as rc;
+    use schema::totp_secret::dsl as ts;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        delete(rc::recovery_code.filter(rc::user_id_fk.eq(user_id))).execute(&conn)?;
+        delete(ts::totp_secret.find(user_id)).execute(&conn)?;
+        Ok(())
+    })
+}
+
+// Accepts a TOTP code newer than the last one used, or burns one of the recovery codes
+fn db_check_second_factor(
+    db: web::Data<Pool>,
+    user_id: i32,
+    code: Option<&str>,
+) -> Result<bool, ServiceError> {
+    use schema::recovery_code::dsl as rc;
+    use schema::totp_secret::dsl as ts;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        let enrolled: Option<TotpSecret> = ts::totp_secret
+            .find(user_id)
+            .filter(ts::enabled.eq(true))
+            .for_update()
+            .get_result(&conn)
+            .optional()?;
+        let enrolled = match enrolled {
+            Some(e) => e,
+            None => return Ok(true),
+        };
+        let code = match code {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+        if let Some(step) = totp::verify_code(&enrolled.secret, code, chrono::Utc::now().timestamp())
+        {
+            if step <= enrolled.last_used_step {
+                return Ok(false);
+            }
+            diesel::update(ts::totp_secret.find(user_id))
+                .set(ts::last_used_step.eq(step))
+                .execute(&conn)?;
+            return Ok(true);
+        }
+        let recovery: Option<RecoveryCode> = rc::recovery_code
+            .filter(rc::user_id_fk.eq(user_id))
+            .filter(rc::code_hash.eq(hash_token(code.trim())))
+            .filter(rc::used.eq(false))
+            .for_update()
+            .get_result(&conn)
+            .optional()?;
+        match recovery {
+            Some(r) => {
+                diesel::update(rc::recovery_code.find(r.id))
+                    .set(rc::used.eq(true))
+                    .execute(&conn)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    })
+}
+
 fn add_single_user(
     db: web::Data<Pool>,
-    item: web::Json<InputUser>,
-) -> Result<User, diesel::result::Error> {
-    let conn = db.get().unwrap();
-    let hashed_password = utils::hash_password(&item.password).unwrap();
+    user_email: &str,
+    password: &str,
+) -> Result<User, ServiceError> {
+    let conn = db.get()?;
+    let hashed_password = utils::hash_password(password)?;
     let new_user = NewUser {
-        email: &item.email,
+        email: user_email,
         pw_hash: &hashed_password,
-        verified: vars::verify_email(),
+        // Only opening the mailed link confirms the address, whether or not writes wait for it
+        verified: false,
         subscribed: false,
         created_at: chrono::Local::now().naive_local(),
         last_payment: chrono::Local::now().naive_local(),
         admin: false,
     };
 
-    let res = insert_into(users).values(&new_user).get_result(&conn)?;
+    let res = insert_into(users)
+        .values(&new_user)
+        .get_result(&conn)
+        .map_err(email_in_use)?;
     Ok(res)
 }
 
-fn db_add_trove_text(db: web::Data<Pool>, user_id: i32, trove_data: &str) -> Result<Trove, diesel::result::Error> {
-    let conn = db.get().unwrap();
-    let new_trove = NewTrove {
-        trove_text: &encode_text(trove_data.to_string())[..],
-        user_id_fk: user_id,
-        created_at: chrono::Local::now().naive_local(),
-    };
-    let res = insert_into(trove).values(&new_trove).get_result(&conn)?;
-    Ok(res)
+// Reports a violation of the unique email index as the email being taken
+fn email_in_use(error: DieselError) -> ServiceError {
+    match error {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            ServiceError::Conflict(String::from("Email already in use"))
+        }
+        e => e.into(),
+    }
+}
+
+// Once the user has `max_revisions`, the oldest revision is deleted to make room. Only one goes per
+// save, so a lower limit, e.g. after a subscription ended, never deletes many revisions at once.
+fn db_add_trove_text(
+    db: web::Data<Pool>,
+    user_id: i32,
+    trove_data: &str,
+    max_revisions: i64,
+) -> Result<Trove, ServiceError> {
+    use schema::trove::dsl as tr;
+    use schema::users::dsl as us;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        // Saves of the same user wait for each other, so they can't both take the last free slot
+        let _: i32 = us::users
+            .find(user_id)
+            .select(us::id)
+            .for_update()
+            .get_result(&conn)?;
+        let revisions: i64 = tr::trove
+            .filter(tr::user_id_fk.eq(user_id))
+            .count()
+            .get_result(&conn)?;
+        if revisions >= max_revisions {
+            let oldest: Option<i32> = tr::trove
+                .filter(tr::user_id_fk.eq(user_id))
+                .order_by(tr::id.asc())
+                .select(tr::id)
+                .first(&conn)
+                .optional()?;
+            if let Some(oldest) = oldest {
+                delete(tr::trove.find(oldest)).execute(&conn)?;
+            }
+        }
+        let new_trove = NewTrove {
+            trove_text: &encode_text(trove_data.to_string())[..],
+            user_id_fk: user_id,
+            created_at: chrono::Local::now().naive_local(),
+        };
+        Ok(insert_into(tr::trove).values(&new_trove).get_result(&conn)?)
+    })
+}
+
+fn db_update_preferences(
+    db: web::Data<Pool>,
+    user_id: i32,
+    change: PreferencesChange,
+) -> Result<User, ServiceError> {
+    use schema::trove::dsl as tr;
+    let conn = db.get()?;
+    conn.transaction(|| {
+        if let Some(Some(trove_id)) = change.default_trove {
+            let owned: i64 = tr::trove
+                .filter(tr::id.eq(trove_id))
+                .filter(tr::user_id_fk.eq(user_id))
+                .count()
+                .get_result(&conn)?;
+            if owned == 0 {
+                return Err(ServiceError::ValidationError(format!(
+                    "Trove {} does not exist",
+                    trove_id
+                )));
+            }
+        }
+        // Diesel refuses to run an update without any changes
+        if change.is_empty() {
+            return Ok(users.find(user_id).get_result(&conn)?);
+        }
+        Ok(diesel::update(users.find(user_id))
+            .set(&change)
+            .get_result(&conn)?)
+    })
 }
 
-fn delete_single_user(db: web::Data<Pool>, user_id: i32) -> Result<usize, diesel::result::Error> {
-    let conn = db.get().unwrap();
-    let count = delete(users.find(user_id)).execute(&conn)?;
+fn db_set_deleted_at(
+    db: web::Data<Pool>,
+    user_id: i32,
+    scheduled_at: Option<chrono::NaiveDateTime>,
+) -> Result<usize, ServiceError> {
+    let conn = db.get()?;
+    let count = diesel::update(users.find(user_id))
+        .set(deleted_at.eq(scheduled_at))
+        .execute(&conn)?;
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parameters named apart from the glob imported columns, which would turn them into patterns
+    fn stored_token(is_used: bool, is_revoked: bool, expires_in: i64) -> RefreshToken {
+        let now = chrono::Local::now().naive_local();
+        RefreshToken {
+            id: 1,
+            token_hash: hash_token("refresh-token"),
+            family: String::from("family-1"),
+            user_id: 7,
+            used: is_used,
+            revoked: is_revoked,
+            created_at: now,
+            expires_at: now + chrono::Duration::seconds(expires_in),
+        }
+    }
+
+    #[test]
+    fn rotates_only_fresh_unused_refresh_tokens() {
+        let now = chrono::Local::now().naive_local();
+        let fresh = stored_token(false, false, 60);
+        assert_eq!(check_refresh_token(&fresh, now), RefreshCheck::Rotate);
+        let revoked = stored_token(false, true, 60);
+        assert_eq!(check_refresh_token(&revoked, now), RefreshCheck::Reject);
+        let expired = stored_token(false, false, -60);
+        assert_eq!(check_refresh_token(&expired, now), RefreshCheck::Reject);
+    }
+
+    #[test]
+    fn reused_refresh_tokens_revoke_their_family() {
+        let now = chrono::Local::now().naive_local();
+        for (is_revoked, expires_in) in [(false, 60), (true, 60), (false, -60)] {
+            let reused = stored_token(true, is_revoked, expires_in);
+            assert_eq!(
+                check_refresh_token(&reused, now),
+                RefreshCheck::RevokeFamily("family-1")
+            );
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::errors::ServiceError;
+use crate::{logging, migrate, Pool};
+use actix_web::{web, Error, HttpResponse};
+use diesel::RunQueryDsl;
+use serde::Serialize;
+use std::time::Duration;
+
+// Versions of the `/v1` style API prefixes this server answers
+pub const API_VERSIONS: &[&str] = &["v1"];
+// Versions of the trove sync protocol clients may speak with this server
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+// A readiness probe shouldn't wait for the pool as long as a request does
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: Check,
+    pub migrations: Check,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn of(result: Result<(), String>) -> Check {
+        match result {
+            Ok(()) => Check {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Check {
+                ok: false,
+                error: Some(e),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub api_versions: &'static [&'static str],
+    pub protocol_versions: &'static [u32],
+}
+
+// Handler for GET /healthz, answers as long as the process serves requests
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+// Handler for GET /readyz, 503 until the database can be queried and has all migrations
+pub async fn readyz(db: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    let readiness = logging::db_block("check_readiness", move || {
+        Ok::<_, ServiceError>(check_readiness(&db))
+    })
+    .await
+    .map_err(ServiceError::from)?;
+    Ok(if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    })
+}
+
+// Handler for GET /version
+pub async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        api_versions: API_VERSIONS,
+        protocol_versions: PROTOCOL_VERSIONS,
+    })
+}
+
+fn check_readiness(pool: &Pool) -> Readiness {
+    let conn = match pool.get_timeout(READINESS_TIMEOUT) {
+        Ok(conn) => conn,
+        Err(e) => {
+            return Readiness {
+                ready: false,
+                database: Check::of(Err(format!("No connection: {}", e))),
+                migrations: Check::of(Err(String::from("Database unavailable"))),
+            }
+        }
+    };
+    let database = diesel::sql_query("SELECT 1")
+        .execute(&conn)
+        .map(|_| ())
+        .map_err(|e| format!("Query failed: {}", e));
+    let migrations = migrate::pending_migrations(&conn).and_then(|pending| {
+        if pending.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Migrations {:?} are pending", pending))
+        }
+    });
+    Readiness {
+        ready: database.is_ok() && migrations.is_ok(),
+        database: Check::of(database),
+        migrations: Check::of(migrations),
+    }
+}
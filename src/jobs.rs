@@ -0,0 +1,239 @@
+use crate::errors::ServiceError;
+use crate::models::{Job, JobRun, NewJobRun};
+use crate::{deletion, logging, quota, schema, subscription, vars, Pool};
+use actix_web::{web, Error, HttpResponse};
+use diesel::prelude::*;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use serde::Serialize;
+use std::time::Duration;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// How long an instance may run a job before others assume it died and take over
+const LEASE: Duration = Duration::from_secs(30 * 60);
+const HISTORY_PER_JOB: i64 = 20;
+
+// A task run every `interval` by one of the instances, returning how many rows it changed
+#[derive(Clone, Copy)]
+pub struct PeriodicJob {
+    pub name: &'static str,
+    pub interval: Duration,
+    pub run: fn(&PgConnection) -> Result<usize, ServiceError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub job: Job,
+    // Newest first
+    pub runs: Vec<JobRun>,
+}
+
+pub fn periodic_jobs() -> Vec<PeriodicJob> {
+    vec![
+        PeriodicJob {
+            name: "purge_deleted_accounts",
+            interval: Duration::from_secs(vars::account_purge_interval()),
+            run: deletion::purge_expired_accounts,
+        },
+        PeriodicJob {
+            name: "cleanup_expired_tokens",
+            interval: HOUR,
+            run: cleanup_expired_tokens,
+        },
+        PeriodicJob {
+            name: "expire_subscriptions",
+            interval: HOUR,
+            run: subscription::expire_lapsed,
+        },
+        PeriodicJob {
+            name: "prune_revisions",
+            interval: DAY,
+            run: quota::prune_revisions,
+        },
+    ]
+}
+
+// Expired refresh and verification tokens, rate limit buckets that refilled long ago, and job runs
+// older than the history `GET /admin/jobs` shows
+fn cleanup_expired_tokens(conn: &PgConnection) -> Result<usize, ServiceError> {
+    use schema::rate_limit_bucket::dsl as rb;
+    use schema::refresh_token::dsl as rt;
+    use schema::verification_token::dsl as vt;
+    let now = chrono::Local::now().naive_local();
+    let refresh = diesel::delete(rt::refresh_token.filter(rt::expires_at.lt(now))).execute(conn)?;
+    let verification =
+        diesel::delete(vt::verification_token.filter(vt::expires_at.lt(now))).execute(conn)?;
+    let buckets = diesel::delete(
+        rb::rate_limit_bucket.filter(rb::updated_at.lt(now - chrono::Duration::days(1))),
+    )
+    .execute(conn)?;
+    let runs = diesel::sql_query(
+        "DELETE FROM job_run WHERE id IN (
+            SELECT id FROM (
+                SELECT id, row_number() OVER (PARTITION BY job ORDER BY id DESC) AS newer
+                FROM job_run
+            ) ranked WHERE newer > $1
+        )",
+    )
+    .bind::<diesel::sql_types::BigInt, _>(HISTORY_PER_JOB)
+    .execute(conn)?;
+    Ok(refresh + verification + buckets + runs)
+}
+
+// Handle to the running scheduler
+pub struct Scheduler {
+    stop: oneshot::Sender<()>,
+    stopped: oneshot::Receiver<()>,
+}
+
+impl Scheduler {
+    // Stops scheduling jobs and waits for a job that is running to finish
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(());
+        let _ = self.stopped.await;
+    }
+}
+
+// Checks every `JOB_POLL_INTERVAL` seconds for due jobs and runs the ones this instance claims.
+// Instances share the schedule through the `job` table, so each run happens on one of them.
+pub fn start(pool: Pool) -> Scheduler {
+    let (stop, mut stop_requested) = oneshot::channel::<()>();
+    let (stopped_tx, stopped) = oneshot::channel();
+    let instance = instance_name();
+    let jobs = periodic_jobs();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(vars::job_poll_interval()));
+        'schedule: loop {
+            let tick = interval.tick();
+            futures::pin_mut!(tick);
+            if let Either::Right(_) = future::select(tick, &mut stop_requested).await {
+                break;
+            }
+            for job in jobs.iter().copied() {
+                let name = job.name;
+                let pool = pool.clone();
+                let instance = instance.clone();
+                match web::block(move || run_if_due(&pool, job, &instance)).await {
+                    Ok(None) => {}
+                    Ok(Some(affected)) => tracing::info!(job = name, affected, "Job finished"),
+                    Err(e) => tracing::error!(job = name, error = ?e, "Job failed"),
+                }
+                // A sent or dropped sender both mean stop
+                if !matches!(stop_requested.try_recv(), Ok(None)) {
+                    break 'schedule;
+                }
+            }
+        }
+        let _ = stopped_tx.send(());
+    });
+    Scheduler { stop, stopped }
+}
+
+fn instance_name() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| String::from("trove"));
+    format!("{}-{}", host, std::process::id())
+}
+
+// Runs the job if it is due and no other instance holds it, recording the run.
+// Returns None if the job wasn't run.
+fn run_if_due(
+    pool: &Pool,
+    job: PeriodicJob,
+    instance: &str,
+) -> Result<Option<usize>, ServiceError> {
+    use schema::job_run::dsl as jr;
+    let conn = pool.get()?;
+    let now = chrono::Local::now().naive_local();
+    if !claim(&conn, &job, instance, now)? {
+        return Ok(None);
+    }
+    let run_id: i32 = diesel::insert_into(jr::job_run)
+        .values(&NewJobRun {
+            job: job.name,
+            instance,
+            started_at: now,
+        })
+        .returning(jr::id)
+        .get_result(&conn)?;
+
+    let result = (job.run)(&conn);
+
+    diesel::update(jr::job_run.find(run_id))
+        .set((
+            jr::finished_at.eq(chrono::Local::now().naive_local()),
+            jr::success.eq(result.is_ok()),
+            jr::affected.eq(result.as_ref().ok().map(|n| *n as i64)),
+            jr::error.eq(result.as_ref().err().map(|e| e.to_string())),
+        ))
+        .execute(&conn)?;
+    release(&conn, job.name, instance)?;
+    result.map(Some)
+}
+
+// Takes the job if it is due and not held, and moves its next run on by its interval
+fn claim(
+    conn: &PgConnection,
+    job: &PeriodicJob,
+    instance: &str,
+    now: chrono::NaiveDateTime,
+) -> Result<bool, diesel::result::Error> {
+    use schema::job::dsl as jb;
+    diesel::insert_into(jb::job)
+        .values((jb::name.eq(job.name), jb::next_run_at.eq(now)))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    let lease = chrono::Duration::seconds(LEASE.as_secs() as i64);
+    let interval = chrono::Duration::seconds(job.interval.as_secs() as i64);
+    let claimed = diesel::update(
+        jb::job
+            .find(job.name)
+            .filter(jb::next_run_at.le(now))
+            .filter(jb::locked_until.is_null().or(jb::locked_until.lt(now))),
+    )
+    .set((
+        jb::locked_by.eq(instance),
+        jb::locked_until.eq(now + lease),
+        jb::next_run_at.eq(now + interval),
+    ))
+    .execute(conn)?;
+    Ok(claimed == 1)
+}
+
+fn release(conn: &PgConnection, name: &str, instance: &str) -> Result<(), diesel::result::Error> {
+    use schema::job::dsl as jb;
+    diesel::update(jb::job.find(name).filter(jb::locked_by.eq(instance)))
+        .set((
+            jb::locked_by.eq(None::<String>),
+            jb::locked_until.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+// Handler for GET /admin/jobs
+pub async fn list_jobs(db: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    Ok(
+        logging::db_block("db_get_job_statuses", move || db_get_job_statuses(db))
+            .await
+            .map(|statuses| HttpResponse::Ok().json(statuses))
+            .map_err(ServiceError::from)?,
+    )
+}
+
+fn db_get_job_statuses(pool: web::Data<Pool>) -> Result<Vec<JobStatus>, ServiceError> {
+    use schema::job::dsl as jb;
+    use schema::job_run::dsl as jr;
+    let conn = pool.get()?;
+    let jobs: Vec<Job> = jb::job.order_by(jb::name).load(&conn)?;
+    let mut statuses = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        let runs = jr::job_run
+            .filter(jr::job.eq(&job.name))
+            .order_by(jr::id.desc())
+            .limit(HISTORY_PER_JOB)
+            .load(&conn)?;
+        statuses.push(JobStatus { job, runs });
+    }
+    Ok(statuses)
+}
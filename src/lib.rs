@@ -0,0 +1,49 @@
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate lazy_static;
+extern crate base64;
+extern crate rand;
+
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod catch_panic;
+pub mod config;
+pub mod deletion;
+pub mod errors;
+pub mod export;
+pub mod file;
+pub mod handlers;
+pub mod health;
+pub mod jobs;
+pub mod logging;
+pub mod mailer;
+pub mod metrics;
+pub mod migrate;
+pub mod models;
+pub mod oidc;
+pub mod quota;
+pub mod ratelimit;
+pub mod request_id;
+pub mod schema;
+pub mod subscription;
+pub mod totp;
+pub mod utils;
+pub mod vars;
+
+pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+// Pool for the configured database, shared by the server and trove-admin
+pub fn create_pool() -> Pool {
+    let manager = ConnectionManager::<PgConnection>::new(vars::database_url());
+    r2d2::Pool::builder()
+        .max_size(config::get().pool_size)
+        .build(manager)
+        .expect("Failed to create pool.")
+}
@@ -0,0 +1,39 @@
+use crate::config::Config;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use std::fmt::Debug;
+use std::future::Future;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+// Installs the subscriber for `tracing` and the `log` records of actix.
+// Nothing logged may contain tokens, passwords or trove contents: requests are logged by route
+// pattern without query or headers, and events only carry ids, counts and error messages.
+pub fn init(config: &Config) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log_level))
+        .with_span_events(FmtSpan::CLOSE);
+    match config.log_format.as_str() {
+        "text" => builder.init(),
+        // The span list carries the request id and user id into every event of a request
+        _ => builder
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .init(),
+    }
+}
+
+// `web::block` for a database call, in a span that ties it to the request and times it
+pub fn db_block<F, I, E>(
+    name: &'static str,
+    f: F,
+) -> impl Future<Output = Result<I, BlockingError<E>>>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: Send + Debug + 'static,
+{
+    let span = tracing::debug_span!("db", call = name);
+    web::block(move || span.in_scope(f))
+}
@@ -0,0 +1,78 @@
+use crate::errors::ServiceError;
+use crate::vars;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+
+pub type SharedMailer = Arc<dyn Mailer + Send + Sync>;
+
+pub trait Mailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), ServiceError>;
+}
+
+// Picks the mailer from `MAILER`, defaulting to the file mailer so local setups need no SMTP server
+pub fn from_env() -> SharedMailer {
+    match vars::mailer().as_str() {
+        "smtp" => Arc::new(SmtpMailer::from_env()),
+        _ => Arc::new(FileMailer {
+            path: vars::mail_file(),
+        }),
+    }
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn from_env() -> SmtpMailer {
+        let transport = SmtpTransport::relay(&vars::smtp_host())
+            .expect("Invalid SMTP_HOST")
+            .credentials(Credentials::new(
+                vars::smtp_username(),
+                vars::smtp_password(),
+            ))
+            .build();
+        SmtpMailer {
+            transport,
+            from: vars::mail_from(),
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), ServiceError> {
+        let message = Message::builder()
+            .from(self.from.parse().map_err(|_| ServiceError::InternalServerError)?)
+            .to(to
+                .parse()
+                .map_err(|_| ServiceError::BadRequest(String::from("Invalid email address")))?)
+            .subject(subject)
+            .body(body.to_string())
+            .map_err(|_| ServiceError::InternalServerError)?;
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(|_| ServiceError::InternalServerError)
+    }
+}
+
+// Appends every mail to a file instead of sending it, for local testing
+pub struct FileMailer {
+    pub path: String,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), ServiceError> {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|_| ServiceError::InternalServerError)?;
+        writeln!(f, "To: {}\nSubject: {}\n\n{}\n---", to, subject, body)
+            .map_err(|_| ServiceError::InternalServerError)
+    }
+}
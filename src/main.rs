@@ -1,63 +1,103 @@
-#[macro_use]
-extern crate diesel;
-extern crate base64;
-extern crate rand;
-
-use actix_web::middleware::Logger;
-use actix_web::{dev::ServiceRequest, web, App, Error, HttpServer};
+use actix_web::{dev::ServiceRequest, web, App, Error, HttpMessage, HttpServer};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::middleware::HttpAuthentication;
-use diesel::prelude::*;
-use diesel::r2d2::{self, ConnectionManager};
-
-mod auth;
-mod errors;
-mod file;
-mod handlers;
-mod models;
-mod schema;
-mod utils;
-mod vars;
-
-pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+use trove_server::{
+    admin, audit, auth, catch_panic, config, errors, handlers, health, jobs, logging, mailer,
+    metrics, migrate, models, quota, ratelimit, request_id, subscription, vars, Pool,
+};
 
 async fn validator(
     req: ServiceRequest,
     credentials: BearerAuth,
     db: web::Data<Pool>,
 ) -> Result<ServiceRequest, Error> {
-    match auth::validate_token(credentials, db) {
-        Ok(res) => {
-            if res {
-                Ok(req)
-            } else {
-                Err(errors::ServiceError::AuthenticationError(String::from(
-                    "Invalid token. Not found",
+    match auth::validate_token(credentials, db).await {
+        Ok(user) => {
+            if user.disabled {
+                return Err(
+                    errors::ServiceError::Forbidden(String::from("Account is disabled")).into(),
+                );
+            }
+            // Accounts scheduled for deletion can only cancel it
+            if user.deleted_at.is_some() && req.path() != "/v1/user/restore" {
+                return Err(errors::ServiceError::Forbidden(String::from(
+                    "Account is scheduled for deletion",
                 ))
-                .into())
+                .into());
             }
+            tracing::Span::current().record("user_id", &user.id);
+            // Handed on to the request quota middleware
+            req.extensions_mut().insert(user);
+            Ok(req)
         }
-        Err(_) => {
-            Err(errors::ServiceError::AuthenticationError(String::from("Invalid token")).into())
+        Err(e) => {
+            metrics::auth_failure("token");
+            Err(e.into())
         }
     }
 }
 
+// Same as `validator`, but only lets admins through
+async fn admin_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+    db: web::Data<Pool>,
+) -> Result<ServiceRequest, Error> {
+    let req = validator(req, credentials, db).await?;
+    let is_admin = req
+        .extensions()
+        .get::<models::User>()
+        .map(|u| u.admin)
+        .unwrap_or(false);
+    if is_admin {
+        Ok(req)
+    } else {
+        Err(errors::ServiceError::Forbidden(String::from("Admins only")).into())
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    dotenv::dotenv().ok();
-    std::env::set_var("RUST_LOG", "actix_web=debug");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match config::Config::load(&args) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("Invalid configuration:\n{}", message);
+            std::process::exit(2);
+        }
+    };
+    if args.iter().any(|arg| arg == "--print-config") {
+        print!("{}", config.redacted());
+        return Ok(());
+    }
+    logging::init(&config);
+    config::init(config);
+    if vars::metrics_token().is_none() {
+        tracing::warn!(
+            "METRICS_TOKEN is not set, anyone who can reach the server can read /metrics"
+        );
+    }
+
+    let pool = trove_server::create_pool();
+    if let Err(message) = migrate::run(&pool, vars::auto_migrate()) {
+        tracing::error!("{}", message);
+        std::process::exit(1);
+    }
+
+    let mailer = mailer::from_env();
+    let limiter = ratelimit::from_env(pool.clone());
+    let (burst, per_minute) = vars::ip_rate_limit();
+    let ip_rule = ratelimit::Rule::per_minute(burst, per_minute);
 
-    let manager = ConnectionManager::<PgConnection>::new(vars::database_url());
-    let pool: Pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
+    let scheduler = if vars::run_jobs() {
+        Some(jobs::start(pool.clone()))
+    } else {
+        None
+    };
 
-    let port = vars::port();
-    let uri = vars::uri();
-    let uri = format!("{}:{}", uri, port);
+    let address = (vars::bind_address(), vars::port());
     // Start http server
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         let auth = HttpAuthentication::bearer(|req, cred| {
             let db = web::Data::new(
                 req.app_data::<Pool>()
@@ -67,22 +107,117 @@ async fn main() -> std::io::Result<()> {
             );
             validator(req, cred, db)
         });
+        let admin_auth = HttpAuthentication::bearer(|req, cred| {
+            let db = web::Data::new(
+                req.app_data::<Pool>()
+                    .expect("Failed to extract DatabaseConnection from ServiceRequest")
+                    .get_ref()
+                    .clone(),
+            );
+            admin_validator(req, cred, db)
+        });
         App::new()
-            .wrap(Logger::default())
+            .wrap(catch_panic::CatchPanic)
+            .wrap(request_id::RequestIdentity)
+            .wrap(metrics::Metrics)
             .data(pool.clone())
+            .data(mailer.clone())
+            .data(limiter.clone())
             .route("/info", web::get().to(handlers::info))
-            .route("/register", web::post().to(handlers::register_user))
-            .route("/token/new", web::get().to(handlers::create_api_token))
+            .route("/metrics", web::get().to(metrics::metrics))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/version", web::get().to(health::version))
+            .service(
+                web::resource("/register")
+                    .wrap(ratelimit::RateLimit::per_ip(limiter.clone(), ip_rule))
+                    .route(web::post().to(handlers::register_user)),
+            )
+            .service(
+                web::resource("/token/new")
+                    .wrap(ratelimit::RateLimit::per_ip(limiter.clone(), ip_rule))
+                    .route(web::get().to(handlers::create_api_token)),
+            )
+            .route("/verify/{token}", web::get().to(handlers::confirm_email))
+            .route(
+                "/webhooks/payment",
+                web::post().to(subscription::payment_webhook),
+            )
+            .route(
+                "/email/confirm/{token}",
+                web::get().to(handlers::confirm_email_change),
+            )
+            .route(
+                "/account/delete/{token}",
+                web::get().to(handlers::confirm_account_deletion),
+            )
+            .service(
+                web::scope("/password")
+                    .wrap(ratelimit::RateLimit::per_ip(limiter.clone(), ip_rule))
+                    .route("/forgot", web::post().to(handlers::forgot_password))
+                    .route("/reset", web::post().to(handlers::reset_password)),
+            )
+            .service(
+                web::scope("/auth")
+                    .wrap(ratelimit::RateLimit::per_ip(limiter.clone(), ip_rule))
+                    .route("/login", web::post().to(handlers::login))
+                    .route("/refresh", web::post().to(handlers::refresh_access_token))
+                    .route("/logout", web::post().to(handlers::logout)),
+            )
             .service(
                 web::scope("/v1")
+                    .wrap(quota::RequestQuota::new(limiter.clone()))
                     .wrap(auth)
                     .route("/trove", web::get().to(handlers::get_trove_by_profile))
                     .route("/trove", web::put().to(handlers::save_trove_by_token))
+                    .route("/usage", web::get().to(handlers::get_usage))
+                    .route(
+                        "/subscription",
+                        web::get().to(subscription::get_subscription),
+                    )
+                    .route("/user", web::get().to(handlers::get_user_by_token))
+                    .route("/user", web::patch().to(handlers::update_preferences))
                     .route("/user", web::delete().to(handlers::delete_user_by_token))
-                    .route("/token/revoke", web::get().to(handlers::revoke_api_token)),
+                    .route("/user/restore", web::post().to(handlers::restore_user))
+                    .route("/user/export", web::get().to(handlers::export_user_data))
+                    .route("/user/audit", web::get().to(audit::get_user_events))
+                    .route("/user/password", web::post().to(handlers::change_password))
+                    .route("/user/email", web::post().to(handlers::request_email_change))
+                    .route("/user/totp/enroll", web::post().to(handlers::enroll_totp))
+                    .route("/user/totp/confirm", web::post().to(handlers::confirm_totp))
+                    .route("/user/totp/disable", web::post().to(handlers::disable_totp))
+                    .route("/token/revoke", web::get().to(handlers::revoke_api_token))
+                    .route(
+                        "/verify/resend",
+                        web::post().to(handlers::resend_verification_email),
+                    ),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(admin_auth)
+                    .route("/stats", web::get().to(admin::get_stats))
+                    .route("/audit", web::get().to(audit::list_events))
+                    .route("/jobs", web::get().to(jobs::list_jobs))
+                    .route("/users", web::get().to(admin::list_users))
+                    .route("/users/{id}", web::get().to(admin::get_user))
+                    .route("/users/{id}", web::patch().to(admin::update_user_flags))
+                    .route("/users/{id}/disable", web::post().to(admin::disable_user))
+                    .route("/users/{id}/enable", web::post().to(admin::enable_user))
+                    .route("/users/{id}/revoke", web::post().to(admin::revoke_tokens))
+                    .route("/users/{id}/troves", web::get().to(admin::list_troves)),
             )
     })
-    .bind(uri)?
-    .run()
-    .await
+    .keep_alive(vars::keep_alive())
+    .shutdown_timeout(vars::shutdown_timeout());
+    if let Some(workers) = vars::workers() {
+        server = server.workers(workers);
+    }
+    // On SIGTERM the server stops accepting connections and returns once in-flight requests,
+    // trove uploads included, finished or `SHUTDOWN_TIMEOUT` ran out
+    let result = server.bind(address)?.run().await;
+    if let Some(scheduler) = scheduler {
+        scheduler.shutdown().await;
+    }
+    tracing::info!("Shut down");
+    result
 }
@@ -0,0 +1,272 @@
+use crate::admin::db_get_stats;
+use crate::errors::ServiceError;
+use crate::logging;
+use crate::utils::hash_token;
+use crate::{vars, Pool};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures::future::{ok, Ready};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// Anything else is counted as "other", so made up methods don't create new series
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+// Counting rows is the expensive part of a scrape, so the counts are refreshed at most this often
+const ENTITY_COUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "trove_http_requests_total",
+        "Handled requests by route and status code",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = prometheus::register_histogram_vec!(
+        "trove_http_request_duration_seconds",
+        "Time to handle a request by route and status code",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref TROVE_UPLOAD_BYTES: Histogram = prometheus::register_histogram!(
+        "trove_upload_bytes",
+        "Size of saved trove uploads",
+        exponential_buckets(256.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref AUTH_FAILURES: IntCounterVec = prometheus::register_int_counter_vec!(
+        "trove_auth_failures_total",
+        "Rejected credentials by kind",
+        &["kind"]
+    )
+    .unwrap();
+    static ref POOL_CONNECTIONS: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "trove_db_pool_connections",
+        "Database connections of the pool by state",
+        &["state"]
+    )
+    .unwrap();
+    static ref POOL_MAX_SIZE: IntGauge = prometheus::register_int_gauge!(
+        "trove_db_pool_max_size",
+        "Most connections the pool opens"
+    )
+    .unwrap();
+    static ref ENTITIES: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "trove_entities",
+        "Number of stored users, active API tokens and trove revisions",
+        &["kind"]
+    )
+    .unwrap();
+    static ref ENTITIES_COUNTED_AT: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+// Counts credentials that were rejected, `kind` tells which check failed
+pub fn auth_failure(kind: &str) {
+    AUTH_FAILURES.with_label_values(&[kind]).inc();
+}
+
+pub fn trove_uploaded(bytes: usize) {
+    TROVE_UPLOAD_BYTES.observe(bytes as f64);
+}
+
+pub fn method_label(method: &str) -> &'static str {
+    METHODS
+        .iter()
+        .find(|m| **m == method)
+        .copied()
+        .unwrap_or("other")
+}
+
+// The pattern of the resource the request is routed to, like `/admin/users/{id}`, so ids and
+// tokens in paths don't create new series. Requests to anything else are "unmatched".
+pub fn route_label(req: &HttpRequest) -> String {
+    req.match_pattern()
+        .unwrap_or_else(|| String::from("unmatched"))
+}
+
+// Whether the entity counts are due, claiming the refresh so concurrent scrapes don't count too
+fn claim_entity_count() -> bool {
+    let mut counted_at = ENTITIES_COUNTED_AT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if counted_at.map_or(false, |t| t.elapsed() < ENTITY_COUNT_INTERVAL) {
+        return false;
+    }
+    *counted_at = Some(Instant::now());
+    true
+}
+
+// Handler for GET /metrics, in the Prometheus text format.
+// Needs `Authorization: Bearer <METRICS_TOKEN>` when a token is configured.
+// Entity counts are served from the last count within `ENTITY_COUNT_INTERVAL`.
+pub async fn metrics(db: web::Data<Pool>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    if let Some(expected) = vars::metrics_token() {
+        let presented = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or("");
+        // Compare digests, so the time taken doesn't tell how much of the token was right
+        if hash_token(presented) != hash_token(&expected) {
+            return Err(
+                ServiceError::AuthenticationError(String::from("Invalid metrics token")).into(),
+            );
+        }
+    }
+
+    let state = db.state();
+    POOL_CONNECTIONS
+        .with_label_values(&["idle"])
+        .set(state.idle_connections as i64);
+    POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set((state.connections - state.idle_connections) as i64);
+    POOL_MAX_SIZE.set(db.max_size() as i64);
+
+    if claim_entity_count() {
+        let stats = match logging::db_block("db_get_stats", move || db_get_stats(db)).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                // Let the next scrape try again
+                *ENTITIES_COUNTED_AT
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+                return Err(ServiceError::from(e).into());
+            }
+        };
+        ENTITIES.with_label_values(&["users"]).set(stats.users);
+        ENTITIES
+            .with_label_values(&["api_tokens"])
+            .set(stats.active_api_tokens);
+        ENTITIES.with_label_values(&["troves"]).set(stats.troves);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|_| ServiceError::InternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
+
+// Middleware recording count and duration of every request.
+// Wrapped outermost, so it sees the status of rendered errors and the time the other middleware take.
+pub struct Metrics;
+
+impl<S, B> Transform<S> for Metrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsMiddleware { service })
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = method_label(req.method().as_str());
+        let route = route_label(req.request());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            let labels = [method, route.as_str(), status.as_str()];
+            HTTP_REQUESTS.with_label_values(&labels).inc();
+            HTTP_DURATION
+                .with_label_values(&labels)
+                .observe(started.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[test]
+    fn labels_unknown_methods_as_other() {
+        assert_eq!(method_label("GET"), "GET");
+        assert_eq!(method_label("PATCH"), "PATCH");
+        assert_eq!(method_label("PROPFIND"), "other");
+        assert_eq!(method_label("get"), "other");
+    }
+
+    #[actix_rt::test]
+    async fn labels_requests_with_the_matched_pattern() {
+        let mut app = test::init_service(App::new().wrap(Metrics).service(
+            web::scope("/metrics-test").route(
+                "/{id}",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            ),
+        ))
+        .await;
+        let counted = |route: &str, status: &str| {
+            HTTP_REQUESTS
+                .with_label_values(&["GET", route, status])
+                .get()
+        };
+        let before = counted("unmatched", "404");
+
+        let req = test::TestRequest::get()
+            .uri("/metrics-test/42")
+            .to_request();
+        assert!(test::call_service(&mut app, req)
+            .await
+            .status()
+            .is_success());
+        assert_eq!(counted("/metrics-test/{id}", "200"), 1);
+
+        let req = test::TestRequest::get()
+            .uri("/metrics-test/42/more")
+            .to_request();
+        test::call_service(&mut app, req).await;
+        assert_eq!(counted("unmatched", "404"), before + 1);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::Pool;
+use diesel::pg::PgConnection;
+use diesel::sql_types::BigInt;
+use diesel::RunQueryDsl;
+use diesel_migrations::{setup_database, MigrationConnection};
+use std::collections::HashSet;
+
+include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+
+embed_migrations!("migrations");
+
+// Key of the advisory lock held while migrating, so instances starting together take turns
+const MIGRATION_LOCK_KEY: i64 = 0x7472_6f76_65;
+
+// Brings the database up to date with the migrations compiled into this binary.
+// Refuses to start against a database a newer release migrated, its schema may not fit our queries.
+pub fn run(pool: &Pool, apply: bool) -> Result<(), String> {
+    let conn = pool
+        .get()
+        .map_err(|e| format!("Could not connect to the database: {}", e))?;
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(&conn)
+        .map_err(|e| format!("Could not take the migration lock: {}", e))?;
+    let result = migrate_locked(&conn, apply);
+    // Closing the connection would release the lock as well, but it goes back into the pool
+    diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(&conn)
+        .map_err(|e| format!("Could not release the migration lock: {}", e))?;
+    result
+}
+
+fn migrate_locked(conn: &PgConnection, apply: bool) -> Result<(), String> {
+    setup_database(conn).map_err(|e| format!("Could not set up migrations table: {}", e))?;
+    let applied = conn
+        .previously_run_migration_versions()
+        .map_err(|e| format!("Could not read applied migrations: {}", e))?;
+    let mut unknown: Vec<&String> = applied
+        .iter()
+        .filter(|v| !MIGRATION_VERSIONS.contains(&v.as_str()))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(format!(
+            "The database schema is ahead of this binary, it has migrations {:?} this release \
+             doesn't know. Deploy a release that includes them.",
+            unknown
+        ));
+    }
+
+    let pending = pending(&applied);
+    if pending.is_empty() {
+        return Ok(());
+    }
+    if !apply {
+        tracing::warn!(
+            ?pending,
+            "Migrations are pending, run them with `trove-admin migrate`"
+        );
+        return Ok(());
+    }
+    tracing::info!(?pending, "Running migrations");
+    embedded_migrations::run(conn).map_err(|e| format!("Migration failed: {}", e))
+}
+
+fn pending(applied: &HashSet<String>) -> Vec<&'static str> {
+    MIGRATION_VERSIONS
+        .iter()
+        .copied()
+        .filter(|v| !applied.contains(*v))
+        .collect()
+}
+
+// Migrations of this binary the database hasn't run yet
+pub fn pending_migrations(conn: &PgConnection) -> Result<Vec<&'static str>, String> {
+    let applied = conn
+        .previously_run_migration_versions()
+        .map_err(|e| format!("Could not read applied migrations: {}", e))?;
+    Ok(pending(&applied))
+}
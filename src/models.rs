@@ -5,12 +5,128 @@ use serde::{Deserialize, Serialize};
 pub struct User {
     pub id: i32,
     pub email: String,
+    #[serde(skip_serializing)]
     pub pw_hash: String,
     pub verified: bool,
     pub created_at: chrono::NaiveDateTime,
     pub subscribed: bool,
     pub last_payment: chrono::NaiveDateTime,
     pub admin: bool,
+    // Set while the account waits for its deletion grace period to run out
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    pub display_name: Option<String>,
+    // Trove revision clients should check out by default
+    pub default_trove: Option<i32>,
+    pub timezone: String,
+    // One of `SYNC_CONFLICT_STRATEGIES`, what clients do when local and remote troves diverged
+    pub sync_conflict_strategy: String,
+    // Set by admins, a disabled account can neither log in nor use its tokens
+    pub disabled: bool,
+    // Name of the plan paid for last, see `subscription::PLANS`
+    pub plan: Option<String>,
+    // `sub` of the identity provider account linked to this one
+    #[serde(skip_serializing)]
+    pub oidc_subject: Option<String>,
+    // When the subscription was canceled, it ends with the period paid for
+    pub canceled_at: Option<chrono::NaiveDateTime>,
+}
+
+pub const SYNC_CONFLICT_STRATEGIES: &[&str] = &["ask", "keep_local", "keep_remote", "newest"];
+
+// What a user may see of their own account, never includes the password hash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub id: i32,
+    pub email: String,
+    pub verified: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub subscribed: bool,
+    pub last_payment: chrono::NaiveDateTime,
+    pub admin: bool,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    pub display_name: Option<String>,
+    pub default_trove: Option<i32>,
+    pub timezone: String,
+    pub sync_conflict_strategy: String,
+    pub disabled: bool,
+    pub plan: Option<String>,
+}
+
+impl From<&User> for UserProfile {
+    fn from(user: &User) -> UserProfile {
+        UserProfile {
+            id: user.id,
+            email: user.email.clone(),
+            verified: user.verified,
+            created_at: user.created_at,
+            subscribed: user.subscribed,
+            last_payment: user.last_payment,
+            admin: user.admin,
+            deleted_at: user.deleted_at,
+            display_name: user.display_name.clone(),
+            default_trove: user.default_trove,
+            timezone: user.timezone.clone(),
+            sync_conflict_strategy: user.sync_conflict_strategy.clone(),
+            disabled: user.disabled,
+            plan: user.plan.clone(),
+        }
+    }
+}
+
+// Preferences changed by PATCH /v1/user, `None` leaves a field as it is
+#[derive(AsChangeset, Debug, Default)]
+#[table_name = "users"]
+pub struct PreferencesChange {
+    pub display_name: Option<Option<String>>,
+    pub default_trove: Option<Option<i32>>,
+    pub timezone: Option<String>,
+    pub sync_conflict_strategy: Option<String>,
+}
+
+impl PreferencesChange {
+    pub fn is_empty(&self) -> bool {
+        self.display_name.is_none()
+            && self.default_trove.is_none()
+            && self.timezone.is_none()
+            && self.sync_conflict_strategy.is_none()
+    }
+}
+
+// Flags set by an admin, `None` leaves a flag as it is
+#[derive(AsChangeset, Debug)]
+#[table_name = "users"]
+pub struct FlagsChange {
+    pub verified: Option<bool>,
+    pub subscribed: Option<bool>,
+    pub plan: Option<Option<String>>,
+    pub canceled_at: Option<Option<chrono::NaiveDateTime>>,
+}
+
+impl FlagsChange {
+    // Setting `subscribed` drops the plan and a cancellation, so a granted subscription doesn't
+    // lapse with an old payment and a revoked one isn't brought back by it
+    pub fn new(verified: Option<bool>, subscribed: Option<bool>) -> FlagsChange {
+        FlagsChange {
+            verified,
+            subscribed,
+            plan: subscribed.map(|_| None),
+            canceled_at: subscribed.map(|_| None),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.verified.is_none() && self.subscribed.is_none()
+    }
+}
+
+// What a payment event changes about its user, `None` leaves a field as it is
+#[derive(AsChangeset, Debug, Default, PartialEq)]
+#[table_name = "users"]
+pub struct SubscriptionChange {
+    pub subscribed: Option<bool>,
+    pub plan: Option<Option<String>>,
+    pub last_payment: Option<chrono::NaiveDateTime>,
+    pub canceled_at: Option<Option<chrono::NaiveDateTime>>,
 }
 
 #[derive(Insertable, Debug)]
@@ -55,3 +171,142 @@ pub struct NewTrove<'a> {
     pub user_id_fk: i32,
     pub created_at: chrono::NaiveDateTime,
 }
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub token_hash: String,
+    pub family: String,
+    pub user_id: i32,
+    pub used: bool,
+    pub revoked: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+}
+#[derive(Insertable, Debug)]
+#[table_name = "refresh_token"]
+pub struct NewRefreshToken<'a> {
+    pub token_hash: &'a str,
+    pub family: &'a str,
+    pub user_id_fk: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct VerificationToken {
+    pub id: i32,
+    pub token_hash: String,
+    pub purpose: String,
+    pub user_id: i32,
+    pub used: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub new_email: Option<String>,
+}
+#[derive(Insertable, Debug)]
+#[table_name = "verification_token"]
+pub struct NewVerificationToken<'a> {
+    pub token_hash: &'a str,
+    pub purpose: &'a str,
+    pub user_id_fk: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub new_email: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct TotpSecret {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+#[derive(Insertable, Debug)]
+#[table_name = "totp_secret"]
+pub struct NewTotpSecret<'a> {
+    pub user_id_fk: i32,
+    pub secret: &'a str,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used: bool,
+}
+#[derive(Insertable, Debug)]
+#[table_name = "recovery_code"]
+pub struct NewRecoveryCode {
+    pub user_id_fk: i32,
+    pub code_hash: String,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "payment_event"]
+pub struct NewPaymentEvent<'a> {
+    pub event_id: &'a str,
+    pub kind: &'a str,
+    pub user_id_fk: i32,
+    pub plan: Option<&'a str>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct AuditEvent {
+    pub id: i32,
+    // Whose account the event is about
+    pub user_id: Option<i32>,
+    // Who did it, differs from `user_id` for admin actions
+    pub actor_id: Option<i32>,
+    pub action: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<serde_json::Value>,
+    pub created_at: chrono::NaiveDateTime,
+}
+#[derive(Insertable, Debug)]
+#[table_name = "audit_event"]
+pub struct NewAuditEvent<'a> {
+    pub user_id_fk: Option<i32>,
+    pub actor_id: Option<i32>,
+    pub action: &'a str,
+    pub ip: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub details: Option<serde_json::Value>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct Job {
+    pub name: String,
+    pub next_run_at: chrono::NaiveDateTime,
+    // Instance running the job right now, its claim ends at `locked_until` even if it died
+    pub locked_by: Option<String>,
+    pub locked_until: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct JobRun {
+    pub id: i32,
+    pub job: String,
+    pub instance: String,
+    pub started_at: chrono::NaiveDateTime,
+    // Unset while the run is going on, or if the instance died during it
+    pub finished_at: Option<chrono::NaiveDateTime>,
+    pub success: Option<bool>,
+    // Rows the job changed or removed
+    pub affected: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "job_run"]
+pub struct NewJobRun<'a> {
+    pub job: &'a str,
+    pub instance: &'a str,
+    pub started_at: chrono::NaiveDateTime,
+}
@@ -0,0 +1,310 @@
+use crate::errors::ServiceError;
+use crate::models::User;
+use crate::vars;
+use alcoholic_jwt::{token_kid, validate, Validation, JWKS};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref JWKS_CACHE: RwLock<JwksCache> = RwLock::new(JwksCache::default());
+}
+
+pub struct OidcIdentity {
+    // The provider's id of the account, accounts stay linked through it when the email changes
+    pub subject: String,
+    pub email: String,
+}
+
+#[derive(Default)]
+struct JwksCache {
+    jwks: Option<Arc<JWKS>>,
+    fetched_at: Option<Instant>,
+    // Last fetch, successful or not
+    attempted_at: Option<Instant>,
+}
+
+impl JwksCache {
+    // A key set that is stale or doesn't know the key id is fetched again, but at most once per
+    // `min_interval`, so tokens with made up key ids can't make us hammer the identity provider
+    fn should_fetch(&self, kid: &str, max_age: Duration, min_interval: Duration) -> bool {
+        let fresh = self.fetched_at.map_or(false, |t| t.elapsed() < max_age);
+        let knows_kid = self.jwks.as_ref().map_or(false, |j| j.find(kid).is_some());
+        if fresh && knows_kid {
+            return false;
+        }
+        self.attempted_at
+            .map_or(true, |t| t.elapsed() >= min_interval)
+    }
+}
+
+pub fn enabled() -> bool {
+    vars::oidc_issuer().is_some()
+}
+
+// Validates a JWT issued by the configured identity provider and returns who it belongs to.
+// May fetch the key set, so it has to run on a blocking thread.
+pub fn validate_identity(token: &str) -> Result<OidcIdentity, ServiceError> {
+    let (issuer, audience) = match (vars::oidc_issuer(), vars::oidc_audience()) {
+        (Some(issuer), Some(audience)) => (issuer, audience),
+        _ => {
+            return Err(ServiceError::AuthenticationError(String::from(
+                "External login is not configured",
+            )))
+        }
+    };
+    let kid = token_kid(token)
+        .ok()
+        .flatten()
+        .ok_or_else(|| ServiceError::AuthenticationError(String::from("Token has no key id")))?;
+    let jwks = cached_jwks(&kid)?;
+    check_identity(token, &kid, &jwks, &issuer, &audience)
+}
+
+fn check_identity(
+    token: &str,
+    kid: &str,
+    jwks: &JWKS,
+    issuer: &str,
+    audience: &str,
+) -> Result<OidcIdentity, ServiceError> {
+    let jwk = jwks
+        .find(kid)
+        .ok_or_else(|| ServiceError::AuthenticationError(String::from("Unknown signing key")))?;
+    let validations = vec![
+        Validation::Issuer(issuer.to_string()),
+        Validation::Audience(audience.to_string()),
+        Validation::NotExpired,
+        Validation::SubjectPresent,
+    ];
+    let valid = validate(token, jwk, validations)
+        .map_err(|_| ServiceError::AuthenticationError(String::from("Invalid identity token")))?;
+
+    // Accounts are linked by email, so it has to be one the provider checked belongs to the user
+    if valid.claims["email_verified"] != serde_json::Value::Bool(true) {
+        return Err(ServiceError::AuthenticationError(String::from(
+            "Email is not verified by the identity provider",
+        )));
+    }
+    let email = valid.claims["email"]
+        .as_str()
+        .ok_or_else(|| ServiceError::AuthenticationError(String::from("Token has no email")))?;
+    let subject = valid.claims["sub"]
+        .as_str()
+        .ok_or_else(|| ServiceError::AuthenticationError(String::from("Token has no subject")))?;
+    Ok(OidcIdentity {
+        subject: subject.to_string(),
+        email: email.to_string(),
+    })
+}
+
+// Which local account a first login through the identity provider takes over, None for a new one.
+// Only an account that confirmed its address qualifies, anyone can register an unconfirmed one
+// for someone else's email and would then share the account with its owner.
+pub fn account_to_link(existing: Option<&User>) -> Result<Option<i32>, ServiceError> {
+    match existing {
+        None => Ok(None),
+        Some(user) if user.oidc_subject.is_some() => Err(ServiceError::AuthenticationError(
+            String::from("The account with this email is linked to another identity"),
+        )),
+        Some(user) if !user.verified => Err(ServiceError::AuthenticationError(String::from(
+            "The account with this email never confirmed it, confirm it or reset its password first",
+        ))),
+        Some(user) => Ok(Some(user.id)),
+    }
+}
+
+// Returns the cached key set, refetching it when it is stale or does not know the key id yet
+fn cached_jwks(kid: &str) -> Result<Arc<JWKS>, ServiceError> {
+    let max_age = Duration::from_secs(vars::oidc_jwks_cache_ttl());
+    let min_interval = Duration::from_secs(vars::oidc_jwks_refetch_interval());
+    {
+        let mut cache = JWKS_CACHE
+            .write()
+            .map_err(|_| ServiceError::JWKSFetchError)?;
+        if !cache.should_fetch(kid, max_age, min_interval) {
+            return cache.jwks.clone().ok_or(ServiceError::JWKSFetchError);
+        }
+        // Claimed before fetching, so concurrent requests use what is cached instead of fetching too
+        cache.attempted_at = Some(Instant::now());
+    }
+    let url = vars::oidc_jwks_url().ok_or(ServiceError::JWKSFetchError)?;
+    let fetched = fetch_jwks(&url);
+    let mut cache = JWKS_CACHE
+        .write()
+        .map_err(|_| ServiceError::JWKSFetchError)?;
+    match fetched {
+        Ok(jwks) => {
+            let jwks = Arc::new(jwks);
+            cache.jwks = Some(jwks.clone());
+            cache.fetched_at = Some(Instant::now());
+            Ok(jwks)
+        }
+        // Keys we already have stay usable while the identity provider is unreachable
+        Err(e) => {
+            tracing::warn!(error = %e, "Could not fetch JWKS");
+            cache.jwks.clone().ok_or(e)
+        }
+    }
+}
+
+// Fetches the key set over http(s), or from disk for `file://` urls used in local setups
+fn fetch_jwks(url: &str) -> Result<JWKS, ServiceError> {
+    if let Some(path) = url.strip_prefix("file://") {
+        let content = std::fs::read_to_string(path).map_err(|_| ServiceError::JWKSFetchError)?;
+        return serde_json::from_str(&content).map_err(|_| ServiceError::JWKSFetchError);
+    }
+    let mut res = reqwest::get(url).map_err(|_| ServiceError::JWKSFetchError)?;
+    res.json::<JWKS>().map_err(|_| ServiceError::JWKSFetchError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+    const ISSUER: &str = "https://sso.example.com";
+    const AUDIENCE: &str = "trove";
+    const KID: &str = "test-key";
+
+    fn jwks() -> JWKS {
+        let url = format!(
+            "file://{}/tests/fixtures/oidc_jwks.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        fetch_jwks(&url).unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        serde_json::json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "sub": "1234",
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "email": "alice@example.com",
+            "email_verified": true,
+        })
+    }
+
+    fn sign(claims: &serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(String::from(KID));
+        let key =
+            EncodingKey::from_rsa_pem(include_bytes!("../tests/fixtures/oidc_private_key.pem"))
+                .unwrap();
+        encode(&header, claims, &key).unwrap()
+    }
+
+    fn check(claims: &serde_json::Value) -> Result<OidcIdentity, ServiceError> {
+        check_identity(&sign(claims), KID, &jwks(), ISSUER, AUDIENCE)
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let token = sign(&claims());
+        assert_eq!(token_kid(&token).unwrap().as_deref(), Some(KID));
+        let identity = check(&claims()).unwrap();
+        assert_eq!(identity.email, "alice@example.com");
+        assert_eq!(identity.subject, "1234");
+    }
+
+    #[test]
+    fn requires_verified_email() {
+        let mut unverified = claims();
+        unverified["email_verified"] = serde_json::Value::Bool(false);
+        assert!(check(&unverified).is_err());
+
+        let mut unknown = claims();
+        unknown.as_object_mut().unwrap().remove("email_verified");
+        assert!(check(&unknown).is_err());
+
+        let mut as_string = claims();
+        as_string["email_verified"] = serde_json::Value::from("true");
+        assert!(check(&as_string).is_err());
+    }
+
+    #[test]
+    fn rejects_other_issuer_audience_and_expired() {
+        let mut other_issuer = claims();
+        other_issuer["iss"] = serde_json::Value::from("https://evil.example.com");
+        assert!(check(&other_issuer).is_err());
+
+        let mut other_audience = claims();
+        other_audience["aud"] = serde_json::Value::from("another-client");
+        assert!(check(&other_audience).is_err());
+
+        let mut expired = claims();
+        expired["exp"] = serde_json::Value::from(chrono::Utc::now().timestamp() - 300);
+        assert!(check(&expired).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_key_and_tampered_token() {
+        let token = sign(&claims());
+        assert!(check_identity(&token, "other-key", &jwks(), ISSUER, AUDIENCE).is_err());
+
+        let mut parts: Vec<String> = token.split('.').map(String::from).collect();
+        let mut forged = claims();
+        forged["email"] = serde_json::Value::from("bob@example.com");
+        parts[1] = base64::encode_config(forged.to_string(), base64::URL_SAFE_NO_PAD);
+        let tampered = parts.join(".");
+        assert!(check_identity(&tampered, KID, &jwks(), ISSUER, AUDIENCE).is_err());
+    }
+
+    #[test]
+    fn refetches_at_most_once_per_interval() {
+        let hour = Duration::from_secs(60 * 60);
+        let minute = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut cache = JwksCache::default();
+        assert!(cache.should_fetch(KID, hour, minute));
+
+        cache.jwks = Some(Arc::new(jwks()));
+        cache.fetched_at = Some(now);
+        cache.attempted_at = Some(now);
+        assert!(!cache.should_fetch(KID, hour, minute));
+        // Unknown key ids don't trigger a fetch right after the last one
+        assert!(!cache.should_fetch("random", hour, minute));
+
+        cache.attempted_at = now.checked_sub(2 * minute);
+        assert!(cache.should_fetch("random", hour, minute));
+        assert!(!cache.should_fetch(KID, hour, minute));
+
+        cache.fetched_at = now.checked_sub(2 * hour);
+        assert!(cache.should_fetch(KID, hour, minute));
+    }
+
+    fn local_user(verified: bool, oidc_subject: Option<&str>) -> User {
+        let now = chrono::Local::now().naive_local();
+        User {
+            id: 7,
+            email: String::from("alice@example.com"),
+            pw_hash: String::new(),
+            verified,
+            created_at: now,
+            subscribed: false,
+            last_payment: now,
+            admin: false,
+            deleted_at: None,
+            display_name: None,
+            default_trove: None,
+            timezone: String::from("UTC"),
+            sync_conflict_strategy: String::from("ask"),
+            disabled: false,
+            plan: None,
+            oidc_subject: oidc_subject.map(String::from),
+            canceled_at: None,
+        }
+    }
+
+    #[test]
+    fn links_only_confirmed_unlinked_accounts() {
+        assert_eq!(account_to_link(None).unwrap(), None);
+        assert_eq!(
+            account_to_link(Some(&local_user(true, None))).unwrap(),
+            Some(7)
+        );
+        // Registered by someone who never proved they own the address
+        assert!(account_to_link(Some(&local_user(false, None))).is_err());
+        assert!(account_to_link(Some(&local_user(true, Some("5678")))).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::errors::ServiceError;
+use crate::logging;
+use crate::models::User;
+use crate::ratelimit::{Rule, SharedLimiter};
+use crate::schema;
+use crate::subscription;
+use crate::vars;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use diesel::prelude::*;
+use futures::future::{ok, Ready};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+// Revisions the prune job deletes at most per run, a lapsed subscription is trimmed over several
+// runs instead of in one long pass
+const PRUNE_BATCH: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Limits {
+    pub requests_per_minute: u32,
+    pub max_trove_bytes: usize,
+    pub max_revisions: i64,
+    pub max_commands: usize,
+}
+
+#[derive(Deserialize)]
+struct TroveCommands {
+    #[serde(default)]
+    commands: Vec<serde_yaml::Value>,
+}
+
+pub fn tier(user: &User) -> &'static str {
+    if subscription::is_active(user) {
+        "subscribed"
+    } else {
+        "free"
+    }
+}
+
+pub fn limits_for(user: &User) -> Limits {
+    if subscription::is_active(user) {
+        vars::subscribed_limits()
+    } else {
+        vars::free_limits()
+    }
+}
+
+// Deletes the oldest revisions of users over their revision limit, e.g. after their subscription
+// ended, at most `PRUNE_BATCH` per run. Returns how many revisions were removed.
+pub fn prune_revisions(conn: &PgConnection) -> Result<usize, ServiceError> {
+    use schema::trove::dsl as tr;
+    use schema::users::dsl as us;
+    let lowest = vars::free_limits()
+        .max_revisions
+        .min(vars::subscribed_limits().max_revisions);
+    let owners: Vec<(i32, i64)> = tr::trove
+        .group_by(tr::user_id_fk)
+        .select((
+            tr::user_id_fk,
+            diesel::dsl::sql::<diesel::sql_types::BigInt>("count(*)"),
+        ))
+        .load(conn)?;
+    let mut pruned = 0;
+    for (user_id, _) in owners.into_iter().filter(|(_, n)| *n > lowest) {
+        if pruned >= PRUNE_BATCH {
+            break;
+        }
+        pruned += conn.transaction::<_, ServiceError, _>(|| {
+            // Locked and counted again, a subscription may have started since the owners were read
+            let user: User = us::users.find(user_id).for_update().get_result(conn)?;
+            let revisions: i64 = tr::trove
+                .filter(tr::user_id_fk.eq(user_id))
+                .count()
+                .get_result(conn)?;
+            let surplus = revisions_to_prune(
+                revisions,
+                limits_for(&user).max_revisions,
+                PRUNE_BATCH - pruned,
+            );
+            if surplus == 0 {
+                return Ok(0);
+            }
+            let oldest: Vec<i32> = tr::trove
+                .filter(tr::user_id_fk.eq(user_id))
+                .order_by(tr::id)
+                .limit(surplus)
+                .select(tr::id)
+                .load(conn)?;
+            Ok(diesel::delete(tr::trove.filter(tr::id.eq_any(&oldest))).execute(conn)? as i64)
+        })?;
+    }
+    Ok(pruned as usize)
+}
+
+// How many of the oldest revisions go, with what is left of the batch
+fn revisions_to_prune(revisions: i64, max_revisions: i64, budget: i64) -> i64 {
+    (revisions - max_revisions).max(0).min(budget.max(0))
+}
+
+// Number of commands in a trove, None if it is no valid trove file
+pub fn count_commands(trove_text: &str) -> Option<usize> {
+    serde_yaml::from_str::<TroveCommands>(trove_text)
+        .ok()
+        .map(|t| t.commands.len())
+}
+
+// Checks an uploaded trove against the limits of its owner. The revision limit isn't checked here,
+// once it is reached saving replaces the oldest revision. Troves that don't parse as trove files
+// are stored as before, they have no commands to count.
+pub fn check_trove(limits: &Limits, trove_text: &str) -> Result<(), ServiceError> {
+    let commands = match count_commands(trove_text) {
+        Some(commands) => commands,
+        None => return Ok(()),
+    };
+    if commands > limits.max_commands {
+        return Err(ServiceError::ValidationError(format!(
+            "Trove has {} commands, the limit is {}",
+            commands, limits.max_commands
+        )));
+    }
+    Ok(())
+}
+
+// Middleware limiting requests per minute for the user the auth validator put into the request
+pub struct RequestQuota {
+    limiter: SharedLimiter,
+}
+
+impl RequestQuota {
+    pub fn new(limiter: SharedLimiter) -> RequestQuota {
+        RequestQuota { limiter }
+    }
+}
+
+impl<S, B> Transform<S> for RequestQuota
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestQuotaMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestQuotaMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+pub struct RequestQuotaMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    limiter: SharedLimiter,
+}
+
+impl<S, B> Service for RequestQuotaMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+        let user = req
+            .extensions()
+            .get::<User>()
+            .map(|u| (u.id, limits_for(u).requests_per_minute));
+        Box::pin(async move {
+            let (user_id, per_minute) = match user {
+                Some(u) => u,
+                None => {
+                    let fut = service.borrow_mut().call(req);
+                    return fut.await;
+                }
+            };
+            let rule = Rule::per_minute(per_minute as f64, per_minute as f64);
+            let verdict = logging::db_block("rate_limit_take", move || {
+                limiter.take(&format!("user:{}", user_id), rule)
+            })
+            .await
+            .map_err(|_| ServiceError::InternalServerError)?;
+            // Errors become responses here, so they carry the headers as well
+            let mut res = match verdict.retry_after {
+                Some(seconds) => {
+                    let (http_req, _) = req.into_parts();
+                    ServiceResponse::from_err(ServiceError::TooManyRequests(seconds), http_req)
+                }
+                None => {
+                    let http_req = req.request().clone();
+                    let fut = service.borrow_mut().call(req);
+                    match fut.await {
+                        Ok(res) => res,
+                        Err(e) => ServiceResponse::from_err(e, http_req),
+                    }
+                }
+            };
+            let reset = ((rule.capacity - verdict.remaining) / rule.per_second).ceil() as u64;
+            let headers = res.headers_mut();
+            for (name, value) in &[
+                ("x-ratelimit-limit", per_minute as u64),
+                ("x-ratelimit-remaining", verdict.remaining.floor() as u64),
+                ("x-ratelimit-reset", reset),
+            ] {
+                headers.insert(HeaderName::from_static(*name), HeaderValue::from(*value));
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::ratelimit::MemoryStore;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use std::sync::Arc;
+
+    const LIMITS: Limits = Limits {
+        requests_per_minute: 60,
+        max_trove_bytes: 1024,
+        max_revisions: 2,
+        max_commands: 2,
+    };
+
+    #[test]
+    fn counts_commands() {
+        assert_eq!(count_commands(include_str!("../examples/git.yml")), Some(1));
+        assert_eq!(count_commands("version: 1.1.1\n"), Some(0));
+        assert_eq!(count_commands("commands: [a, b, c]"), Some(3));
+        assert_eq!(count_commands("commands: nope"), None);
+        assert_eq!(count_commands("{"), None);
+    }
+
+    #[test]
+    fn checks_trove_against_limits() {
+        assert!(check_trove(&LIMITS, "commands: [a, b]").is_ok());
+        assert!(matches!(
+            check_trove(&LIMITS, "commands: [a, b, c]"),
+            Err(ServiceError::ValidationError(_))
+        ));
+        assert!(check_trove(&LIMITS, "commands: nope").is_ok());
+        assert!(check_trove(&LIMITS, "not a trove file").is_ok());
+    }
+
+    #[test]
+    fn prunes_only_the_surplus_within_the_batch() {
+        assert_eq!(revisions_to_prune(1, LIMITS.max_revisions, PRUNE_BATCH), 0);
+        assert_eq!(revisions_to_prune(2, LIMITS.max_revisions, PRUNE_BATCH), 0);
+        assert_eq!(revisions_to_prune(5, LIMITS.max_revisions, PRUNE_BATCH), 3);
+        assert_eq!(revisions_to_prune(5, LIMITS.max_revisions, 1), 1);
+        assert_eq!(revisions_to_prune(5, LIMITS.max_revisions, 0), 0);
+        assert_eq!(revisions_to_prune(5000, 100, PRUNE_BATCH), PRUNE_BATCH);
+    }
+
+    fn free_user() -> User {
+        let now = chrono::Local::now().naive_local();
+        User {
+            id: 1,
+            email: String::from("alice@example.com"),
+            pw_hash: String::new(),
+            verified: true,
+            created_at: now,
+            subscribed: false,
+            last_payment: now,
+            admin: false,
+            deleted_at: None,
+            display_name: None,
+            default_trove: None,
+            timezone: String::from("UTC"),
+            sync_conflict_strategy: String::from("ask"),
+            disabled: false,
+            plan: None,
+            oidc_subject: None,
+            canceled_at: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rate_limit_headers_come_with_errors_too() {
+        config::init_for_tests();
+        let per_minute = vars::free_limits().requests_per_minute;
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestQuota::new(Arc::new(MemoryStore::default())))
+                .wrap_fn(|req, srv| {
+                    req.extensions_mut().insert(free_user());
+                    srv.call(req)
+                })
+                .route(
+                    "/ok",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .route(
+                    "/missing",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(ServiceError::NotFound(String::from("Not found")))
+                    }),
+                ),
+        )
+        .await;
+        let header = |res: &ServiceResponse, name: &str| -> u64 {
+            res.headers()
+                .get(name)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        let req = test::TestRequest::get().uri("/missing").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(header(&res, "x-ratelimit-limit"), per_minute as u64);
+        assert_eq!(header(&res, "x-ratelimit-remaining"), per_minute as u64 - 1);
+
+        for _ in 1..per_minute {
+            let req = test::TestRequest::get().uri("/ok").to_request();
+            assert_eq!(
+                test::call_service(&mut app, req).await.status(),
+                StatusCode::OK
+            );
+        }
+        let req = test::TestRequest::get().uri("/ok").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&res, "x-ratelimit-remaining"), 0);
+        assert!(res.headers().contains_key("retry-after"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::errors::ServiceError;
+use crate::schema::rate_limit_bucket::dsl::*;
+use crate::utils::client_ip;
+use crate::{logging, vars, Pool};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{web, Error};
+use diesel::prelude::*;
+use diesel::dsl::insert_into;
+use futures::future::{ok, Ready};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+// Above this many tracked keys the in-memory store drops buckets that refilled completely
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+pub type SharedLimiter = Arc<dyn RateLimitStore + Send + Sync>;
+
+// Token bucket holding `capacity` requests, refilling with `per_second` requests per second
+#[derive(Debug, Clone, Copy)]
+pub struct Rule {
+    pub capacity: f64,
+    pub per_second: f64,
+}
+
+impl Rule {
+    pub fn per_minute(capacity: f64, per_minute: f64) -> Rule {
+        Rule {
+            capacity,
+            per_second: per_minute / 60.0,
+        }
+    }
+}
+
+// Outcome of taking a token, `retry_after` is set when the bucket was empty
+#[derive(Debug, Clone, Copy)]
+pub struct Verdict {
+    pub remaining: f64,
+    pub retry_after: Option<u64>,
+}
+
+pub trait RateLimitStore {
+    // Takes one token from the bucket of `key`
+    fn take(&self, key: &str, rule: Rule) -> Result<Verdict, ServiceError>;
+}
+
+// Picks the store from `RATE_LIMIT_STORE`, postgres shares the buckets between instances
+pub fn from_env(pool: Pool) -> SharedLimiter {
+    match vars::rate_limit_store().as_str() {
+        "postgres" => Arc::new(PostgresStore { pool }),
+        _ => Arc::new(MemoryStore::default()),
+    }
+}
+
+pub async fn throttle(limiter: web::Data<SharedLimiter>, key: String, rule: Rule) -> Result<(), Error> {
+    let verdict = logging::db_block("rate_limit_take", move || limiter.take(&key, rule))
+        .await
+        .map_err(|_| ServiceError::InternalServerError)?;
+    match verdict.retry_after {
+        Some(seconds) => Err(ServiceError::TooManyRequests(seconds).into()),
+        None => Ok(()),
+    }
+}
+
+// Refills the bucket for the elapsed time and takes a token if one is available
+fn refill(tokens_left: f64, elapsed_seconds: f64, rule: Rule) -> Verdict {
+    let available = (tokens_left + elapsed_seconds * rule.per_second).min(rule.capacity);
+    if available >= 1.0 {
+        Verdict {
+            remaining: available - 1.0,
+            retry_after: None,
+        }
+    } else {
+        let wait = ((1.0 - available) / rule.per_second).ceil() as u64;
+        Verdict {
+            remaining: available,
+            retry_after: Some(wait.max(1)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take(&self, key: &str, rule: Rule) -> Result<Verdict, ServiceError> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| ServiceError::InternalServerError)?;
+        let now = Instant::now();
+        if buckets.len() > MAX_TRACKED_KEYS {
+            buckets.retain(|_, (left, last)| {
+                *left + now.duration_since(*last).as_secs_f64() * rule.per_second < rule.capacity
+            });
+        }
+        let (left, last) = buckets
+            .get(key)
+            .copied()
+            .unwrap_or((rule.capacity, now));
+        let verdict = refill(left, now.duration_since(last).as_secs_f64(), rule);
+        buckets.insert(key.to_string(), (verdict.remaining, now));
+        Ok(verdict)
+    }
+}
+
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl RateLimitStore for PostgresStore {
+    fn take(&self, key: &str, rule: Rule) -> Result<Verdict, ServiceError> {
+        let conn = self
+            .pool
+            .get()
+            .map_err(|_| ServiceError::InternalServerError)?;
+        let now = chrono::Local::now().naive_local();
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            insert_into(rate_limit_bucket)
+                .values((bucket_key.eq(key), tokens.eq(rule.capacity), updated_at.eq(now)))
+                .on_conflict_do_nothing()
+                .execute(&conn)?;
+            let (left, last): (f64, chrono::NaiveDateTime) = rate_limit_bucket
+                .find(key)
+                .select((tokens, updated_at))
+                .for_update()
+                .get_result(&conn)?;
+            let elapsed = (now - last).num_milliseconds().max(0) as f64 / 1000.0;
+            let verdict = refill(left, elapsed, rule);
+            diesel::update(rate_limit_bucket.find(key))
+                .set((tokens.eq(verdict.remaining), updated_at.eq(now)))
+                .execute(&conn)?;
+            Ok(verdict)
+        })
+        .map_err(|_| ServiceError::InternalServerError)
+    }
+}
+
+// Middleware limiting requests per client ip
+pub struct RateLimit {
+    limiter: SharedLimiter,
+    rule: Rule,
+}
+
+impl RateLimit {
+    pub fn per_ip(limiter: SharedLimiter, rule: Rule) -> RateLimit {
+        RateLimit { limiter, rule }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limiter: self.limiter.clone(),
+            rule: self.rule,
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    limiter: SharedLimiter,
+    rule: Rule,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = web::Data::new(self.limiter.clone());
+        let rule = self.rule;
+        let key = format!("ip:{}:{}", req.path(), client_ip(req.peer_addr(), req.headers()));
+        Box::pin(async move {
+            throttle(limiter, key, rule).await?;
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
@@ -0,0 +1,224 @@
+use crate::errors::{ErrorBody, ServiceError};
+use crate::metrics::route_label;
+use crate::utils::generate_request_id;
+use actix_service::{Service, Transform};
+use actix_web::dev::{Body, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderName, HeaderValue, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures::future::{ok, Ready};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::Instrument;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Id of the current request, available from the request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// Renders any error as the JSON error body, including the id of the request it happened in.
+// Only the body of `original`, the response the error made, is replaced, so headers like
+// `WWW-Authenticate` on a 401 stay.
+pub fn error_response(err: &Error, original: &HttpResponse, request_id: &str) -> HttpResponse {
+    let status = original.status();
+    if status.is_server_error() {
+        tracing::error!(error = %err, "Request failed");
+    }
+    let mut res = match err.as_error::<ServiceError>() {
+        Some(service_error) => service_error.response_with_request_id(Some(request_id.to_string())),
+        None => {
+            let (code, message) = if status.is_server_error() {
+                (
+                    String::from("internal_error"),
+                    String::from("Internal Server Error, Please try later"),
+                )
+            } else {
+                (status_code_name(status), err.to_string())
+            };
+            HttpResponse::build(status).json(ErrorBody {
+                code,
+                message,
+                details: None,
+                request_id: Some(request_id.to_string()),
+            })
+        }
+    };
+    for (name, value) in original.headers().iter() {
+        if name != header::CONTENT_TYPE
+            && name != header::CONTENT_LENGTH
+            && !res.headers().contains_key(name)
+        {
+            res.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    res
+}
+
+// "Bad Request" becomes "bad_request"
+fn status_code_name(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_lowercase()
+        .replace(' ', "_")
+}
+
+// Only accept ids from clients that are safe to echo into headers and logs
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Middleware taking the request id from `X-Request-Id` or generating one, echoing it in the
+// response and turning every error into the structured JSON error body.
+// Handles the request in a span carrying the id, which the validator adds the user id to, and
+// logs it by route pattern, so tokens in paths and query strings never reach the logs.
+// Has to wrap the app's routes with only `CatchPanic` inside it, so it sees the plain response
+// body, panics included.
+pub struct RequestIdentity;
+
+impl<S> Transform<S> for RequestIdentity
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdentityMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdentityMiddleware { service })
+    }
+}
+
+pub struct RequestIdentityMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for RequestIdentityMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| is_valid_request_id(v))
+            .map(String::from)
+            .unwrap_or_else(generate_request_id);
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+        let http_req = req.request().clone();
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            route = %route_label(req.request()),
+            user_id = tracing::field::Empty,
+        );
+        let started = Instant::now();
+        let service = &mut self.service;
+        let fut = span.in_scope(|| service.call(req));
+
+        let handled = async move {
+            let mut res = match fut.await {
+                Ok(res) => {
+                    let rendered = res
+                        .response()
+                        .error()
+                        .map(|err| error_response(err, res.response(), &request_id));
+                    match rendered {
+                        Some(rendered) => res.into_response(rendered),
+                        None => res,
+                    }
+                }
+                Err(err) => {
+                    let original = err.as_response_error().error_response();
+                    ServiceResponse::new(http_req, error_response(&err, &original, &request_id))
+                }
+            };
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            tracing::info!(
+                status = res.status().as_u16(),
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "Handled request"
+            );
+            Ok(res)
+        };
+        Box::pin(handled.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, ResponseError};
+    use derive_more::Display;
+
+    // Fails like a missing bearer token does, with a challenge header
+    #[derive(Debug, Display)]
+    #[display(fmt = "Missing token")]
+    struct MissingToken;
+
+    impl ResponseError for MissingToken {
+        fn status_code(&self) -> StatusCode {
+            StatusCode::UNAUTHORIZED
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .finish()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn keeps_the_headers_of_error_responses() {
+        let mut app = test::init_service(App::new().wrap(RequestIdentity).route(
+            "/",
+            web::get().to(|| async { Err::<HttpResponse, _>(MissingToken) }),
+        ))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "unauthorized");
+        assert_eq!(body["message"], "Missing token");
+        assert_eq!(body["request_id"], "abc-123");
+    }
+}
@@ -1,5 +1,26 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    account_lockout (user_id_fk) {
+        user_id_fk -> Int4,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    audit_event (id) {
+        id -> Int4,
+        user_id_fk -> Nullable<Int4>,
+        actor_id -> Nullable<Int4>,
+        action -> Text,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        details -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     api_token (id) {
         id -> Int4,
@@ -10,6 +31,79 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    job (name) {
+        name -> Text,
+        next_run_at -> Timestamp,
+        locked_by -> Nullable<Text>,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    job_run (id) {
+        id -> Int4,
+        job -> Text,
+        instance -> Text,
+        started_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+        success -> Nullable<Bool>,
+        affected -> Nullable<Int8>,
+        error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    payment_event (id) {
+        id -> Int4,
+        event_id -> Text,
+        kind -> Text,
+        user_id_fk -> Int4,
+        plan -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    rate_limit_bucket (bucket_key) {
+        bucket_key -> Text,
+        tokens -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_code (id) {
+        id -> Int4,
+        user_id_fk -> Int4,
+        code_hash -> Text,
+        used -> Bool,
+    }
+}
+
+diesel::table! {
+    refresh_token (id) {
+        id -> Int4,
+        token_hash -> Text,
+        family -> Text,
+        user_id_fk -> Int4,
+        used -> Bool,
+        revoked -> Bool,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    totp_secret (user_id_fk) {
+        user_id_fk -> Int4,
+        secret -> Text,
+        enabled -> Bool,
+        last_used_step -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     trove (id) {
         id -> Int4,
@@ -29,11 +123,43 @@ diesel::table! {
         subscribed -> Bool,
         last_payment -> Timestamp,
         admin -> Bool,
+        deleted_at -> Nullable<Timestamp>,
+        display_name -> Nullable<Text>,
+        default_trove -> Nullable<Int4>,
+        timezone -> Text,
+        sync_conflict_strategy -> Text,
+        disabled -> Bool,
+        plan -> Nullable<Text>,
+        oidc_subject -> Nullable<Text>,
+        canceled_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    verification_token (id) {
+        id -> Int4,
+        token_hash -> Text,
+        purpose -> Text,
+        user_id_fk -> Int4,
+        used -> Bool,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        new_email -> Nullable<Text>,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    account_lockout,
     api_token,
+    audit_event,
+    job,
+    job_run,
+    payment_event,
+    rate_limit_bucket,
+    recovery_code,
+    refresh_token,
+    totp_secret,
     trove,
     users,
+    verification_token,
 );
@@ -0,0 +1,515 @@
+use crate::auth::authenticated_user;
+use crate::errors::ServiceError;
+use crate::logging;
+use crate::models::{NewPaymentEvent, SubscriptionChange, User};
+use crate::schema;
+use crate::vars;
+use crate::Pool;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// `t=<unix time>,v1=<hex hmac-sha256 of "<unix time>.<body>">`
+pub const SIGNATURE_HEADER: &str = "x-trove-signature";
+// Older signatures are rejected, so captured requests can't be replayed later
+const SIGNATURE_TOLERANCE: i64 = 5 * 60;
+
+pub const PAYMENT_SUCCEEDED: &str = "payment.succeeded";
+pub const SUBSCRIPTION_CANCELED: &str = "subscription.canceled";
+
+pub struct Plan {
+    pub name: &'static str,
+    // How long a payment keeps the subscription going
+    pub period_days: i64,
+}
+
+pub const PLANS: &[Plan] = &[
+    Plan {
+        name: "monthly",
+        period_days: 31,
+    },
+    Plan {
+        name: "yearly",
+        period_days: 366,
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    // Never subscribed, or canceled and the paid period is over
+    None,
+    Active,
+    // Expired, but premium features keep working until the grace period ran out
+    Grace,
+    Expired,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Subscription {
+    pub status: Status,
+    pub plan: Option<String>,
+    pub last_payment: chrono::NaiveDateTime,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub grace_until: Option<chrono::NaiveDateTime>,
+    pub canceled_at: Option<chrono::NaiveDateTime>,
+}
+
+// Event sent by the payment provider
+#[derive(Debug, Deserialize)]
+pub struct PaymentEvent {
+    // Unique per event, the provider may deliver an event more than once
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub user_id: i32,
+    pub plan: Option<String>,
+    pub paid_at: Option<chrono::NaiveDateTime>,
+}
+
+pub fn find_plan(name: &str) -> Option<&'static Plan> {
+    PLANS.iter().find(|p| p.name == name)
+}
+
+// When the last payment runs out. Subscriptions without a plan were granted by an admin and don't expire.
+pub fn expires_at(user: &User) -> Option<chrono::NaiveDateTime> {
+    user.plan
+        .as_deref()
+        .and_then(find_plan)
+        .map(|p| user.last_payment + chrono::Duration::days(p.period_days))
+}
+
+pub fn status(user: &User, now: chrono::NaiveDateTime) -> Status {
+    if !user.subscribed {
+        return Status::None;
+    }
+    match expires_at(user) {
+        None => Status::Active,
+        Some(expiry) if now < expiry => Status::Active,
+        // No payment is coming that a grace period could wait for
+        Some(_) if user.canceled_at.is_some() => Status::None,
+        Some(expiry) if now < expiry + grace_period() => Status::Grace,
+        Some(_) => Status::Expired,
+    }
+}
+
+// Whether the user gets premium features right now
+pub fn is_active(user: &User) -> bool {
+    match status(user, chrono::Local::now().naive_local()) {
+        Status::Active | Status::Grace => true,
+        Status::None | Status::Expired => false,
+    }
+}
+
+// Ends the subscriptions that stayed unpaid past the grace period or were canceled and ran out,
+// returns how many
+pub fn expire_lapsed(conn: &PgConnection) -> Result<usize, ServiceError> {
+    use schema::users::dsl as us;
+    let now = chrono::Local::now().naive_local();
+    let subscribers: Vec<User> = us::users
+        .filter(us::subscribed.eq(true))
+        .filter(us::plan.is_not_null())
+        .load(conn)?;
+    let lapsed: Vec<i32> = subscribers
+        .iter()
+        .filter(|u| matches!(status(u, now), Status::Expired | Status::None))
+        .map(|u| u.id)
+        .collect();
+    if lapsed.is_empty() {
+        return Ok(0);
+    }
+    Ok(diesel::update(us::users.filter(us::id.eq_any(&lapsed)))
+        .set(us::subscribed.eq(false))
+        .execute(conn)?)
+}
+
+fn grace_period() -> chrono::Duration {
+    chrono::Duration::seconds(vars::subscription_grace_period())
+}
+
+fn signature(secret: &str, timestamp: i64, body: &[u8]) -> Result<Hmac<Sha256>, ServiceError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| ServiceError::InternalServerError)?;
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Ok(mac)
+}
+
+// Value of the signature header for a body, what the payment provider or a local stub sends
+pub fn signature_header(secret: &str, timestamp: i64, body: &[u8]) -> Result<String, ServiceError> {
+    let mac = signature(secret, timestamp, body)?;
+    Ok(format!(
+        "t={},v1={}",
+        timestamp,
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+pub fn verify_signature(
+    secret: &str,
+    header: &str,
+    body: &[u8],
+    now: i64,
+) -> Result<(), ServiceError> {
+    let invalid = || ServiceError::AuthenticationError(String::from("Invalid webhook signature"));
+    let mut timestamp = None;
+    let mut expected = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => expected = hex::decode(value).ok(),
+            _ => {}
+        }
+    }
+    let (timestamp, expected) = match (timestamp, expected) {
+        (Some(t), Some(e)) => (t, e),
+        _ => return Err(invalid()),
+    };
+    if (now - timestamp).abs() > SIGNATURE_TOLERANCE {
+        return Err(invalid());
+    }
+    // Constant time comparison
+    signature(secret, timestamp, body)?
+        .verify_slice(&expected)
+        .map_err(|_| invalid())
+}
+
+// Handler for POST /webhooks/payment
+pub async fn payment_webhook(
+    db: web::Data<Pool>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let secret = vars::payment_webhook_secret().ok_or_else(|| {
+        ServiceError::Forbidden(String::from("Payment webhook is not configured"))
+    })?;
+    let header = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ServiceError::AuthenticationError(String::from("Missing webhook signature")))?;
+    verify_signature(&secret, header, &body, chrono::Utc::now().timestamp())?;
+    let event: PaymentEvent = serde_json::from_slice(&body)
+        .map_err(|e| ServiceError::BadRequest(format!("Invalid event: {}", e)))?;
+    Ok(logging::db_block("db_apply_event", move || db_apply_event(db, event))
+        .await
+        .map(|_| HttpResponse::Ok().json("Processed event"))
+        .map_err(ServiceError::from)?)
+}
+
+// Handler for GET /v1/subscription
+pub async fn get_subscription(db: web::Data<Pool>, auth: BearerAuth) -> Result<HttpResponse, Error> {
+    let user = authenticated_user(db, auth).await?;
+    let expiry = expires_at(&user);
+    Ok(HttpResponse::Ok().json(Subscription {
+        status: status(&user, chrono::Local::now().naive_local()),
+        plan: user.plan.clone(),
+        last_payment: user.last_payment,
+        expires_at: expiry,
+        grace_until: expiry
+            .filter(|_| user.canceled_at.is_none())
+            .map(|e| e + grace_period()),
+        canceled_at: user.canceled_at,
+    }))
+}
+
+// What an event changes about its user, None if nothing
+pub fn change_for(
+    user: &User,
+    event: &PaymentEvent,
+    now: chrono::NaiveDateTime,
+) -> Option<SubscriptionChange> {
+    let happened_at = event.paid_at.unwrap_or(now);
+    // Deliveries can come out of order, an event from before the last payment is only recorded
+    if happened_at < user.last_payment {
+        return None;
+    }
+    match event.kind.as_str() {
+        PAYMENT_SUCCEEDED => Some(SubscriptionChange {
+            subscribed: Some(true),
+            plan: Some(event.plan.clone()),
+            last_payment: Some(happened_at),
+            canceled_at: Some(None),
+        }),
+        // The period paid for isn't taken away, the subscription ends with it
+        SUBSCRIPTION_CANCELED if user.plan.is_some() => Some(SubscriptionChange {
+            canceled_at: Some(Some(happened_at)),
+            ..SubscriptionChange::default()
+        }),
+        // Granted by an admin, there is no paid period to keep
+        SUBSCRIPTION_CANCELED => Some(SubscriptionChange {
+            subscribed: Some(false),
+            ..SubscriptionChange::default()
+        }),
+        // Providers send many kinds of events, the rest are only recorded
+        _ => None,
+    }
+}
+
+// Applies an event once, returns false if it was processed before
+fn db_apply_event(pool: web::Data<Pool>, event: PaymentEvent) -> Result<bool, ServiceError> {
+    use schema::payment_event::dsl as pe;
+    use schema::users::dsl as us;
+    let conn = pool.get()?;
+    conn.transaction(|| {
+        let user: User = us::users
+            .find(event.user_id)
+            .for_update()
+            .get_result(&conn)?;
+        if event.kind == PAYMENT_SUCCEEDED {
+            let known = event.plan.as_deref().and_then(find_plan).is_some();
+            if !known {
+                return Err(ServiceError::ValidationError(format!(
+                    "Unknown plan {:?}",
+                    event.plan
+                )));
+            }
+        }
+        let now = chrono::Local::now().naive_local();
+        let inserted = diesel::insert_into(pe::payment_event)
+            .values(&NewPaymentEvent {
+                event_id: &event.id,
+                kind: &event.kind,
+                user_id_fk: event.user_id,
+                plan: event.plan.as_deref(),
+                created_at: now,
+            })
+            .on_conflict(pe::event_id)
+            .do_nothing()
+            .execute(&conn)?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        if let Some(change) = change_for(&user, &event, now) {
+            diesel::update(us::users.find(user.id))
+                .set(&change)
+                .execute(&conn)?;
+        }
+        Ok(true)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, TEST_WEBHOOK_SECRET};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use diesel::r2d2::ConnectionManager;
+
+    const NOW: i64 = 1_800_000_000;
+
+    fn subscriber(plan: Option<&str>, last_payment: chrono::NaiveDateTime) -> User {
+        User {
+            id: 1,
+            email: String::from("alice@example.com"),
+            pw_hash: String::new(),
+            verified: true,
+            created_at: last_payment,
+            subscribed: true,
+            last_payment,
+            admin: false,
+            deleted_at: None,
+            display_name: None,
+            default_trove: None,
+            timezone: String::from("UTC"),
+            sync_conflict_strategy: String::from("ask"),
+            disabled: false,
+            plan: plan.map(String::from),
+            oidc_subject: None,
+            canceled_at: None,
+        }
+    }
+
+    #[test]
+    fn status_follows_payment_and_grace_period() {
+        config::init_for_tests();
+        let paid = chrono::NaiveDate::from_ymd_opt(2027, 1, 15)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        let days = |n| paid + chrono::Duration::days(n);
+        let monthly = subscriber(Some("monthly"), paid);
+        assert_eq!(status(&monthly, days(30)), Status::Active);
+        // 31 days of the plan, then 7 days of grace
+        assert_eq!(status(&monthly, days(32)), Status::Grace);
+        assert_eq!(status(&monthly, days(39)), Status::Expired);
+        assert_eq!(
+            status(&subscriber(Some("yearly"), paid), days(365)),
+            Status::Active
+        );
+
+        let granted = subscriber(None, paid);
+        assert_eq!(status(&granted, days(1000)), Status::Active);
+        let canceled = User {
+            subscribed: false,
+            ..subscriber(Some("monthly"), paid)
+        };
+        assert_eq!(status(&canceled, days(1)), Status::None);
+    }
+
+    fn event(
+        kind: &str,
+        plan: Option<&str>,
+        paid_at: Option<chrono::NaiveDateTime>,
+    ) -> PaymentEvent {
+        PaymentEvent {
+            id: String::from("evt_1"),
+            kind: String::from(kind),
+            user_id: 1,
+            plan: plan.map(String::from),
+            paid_at,
+        }
+    }
+
+    #[test]
+    fn canceled_subscriptions_last_until_the_paid_period_ends() {
+        config::init_for_tests();
+        let paid = chrono::NaiveDate::from_ymd_opt(2027, 1, 15)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        let days = |n| paid + chrono::Duration::days(n);
+        let monthly = subscriber(Some("monthly"), paid);
+        let change = change_for(&monthly, &event(SUBSCRIPTION_CANCELED, None, None), days(3));
+        assert_eq!(
+            change,
+            Some(SubscriptionChange {
+                canceled_at: Some(Some(days(3))),
+                ..SubscriptionChange::default()
+            })
+        );
+
+        let canceled = User {
+            canceled_at: Some(days(3)),
+            ..subscriber(Some("monthly"), paid)
+        };
+        assert_eq!(status(&canceled, days(30)), Status::Active);
+        // No grace period, nobody is going to pay
+        assert_eq!(status(&canceled, days(32)), Status::None);
+
+        // Granted subscriptions have no paid period and end right away
+        let granted = subscriber(None, paid);
+        let change = change_for(&granted, &event(SUBSCRIPTION_CANCELED, None, None), days(3));
+        assert_eq!(change.and_then(|c| c.subscribed), Some(false));
+    }
+
+    #[test]
+    fn payments_renew_and_undo_cancellations() {
+        let paid = chrono::NaiveDate::from_ymd_opt(2027, 1, 15)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        let renewed = paid + chrono::Duration::days(31);
+        let canceled = User {
+            canceled_at: Some(paid),
+            ..subscriber(Some("monthly"), paid)
+        };
+        let payment = event(PAYMENT_SUCCEEDED, Some("yearly"), Some(renewed));
+        assert_eq!(
+            change_for(&canceled, &payment, renewed),
+            Some(SubscriptionChange {
+                subscribed: Some(true),
+                plan: Some(Some(String::from("yearly"))),
+                last_payment: Some(renewed),
+                canceled_at: Some(None),
+            })
+        );
+        assert_eq!(
+            change_for(&canceled, &event("invoice.created", None, None), renewed),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_events_from_before_the_last_payment() {
+        let paid = chrono::NaiveDate::from_ymd_opt(2027, 1, 15)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        let earlier = paid - chrono::Duration::days(31);
+        let monthly = subscriber(Some("monthly"), paid);
+        let late_payment = event(PAYMENT_SUCCEEDED, Some("monthly"), Some(earlier));
+        assert_eq!(change_for(&monthly, &late_payment, paid), None);
+        let late_cancel = event(SUBSCRIPTION_CANCELED, None, Some(earlier));
+        assert_eq!(change_for(&monthly, &late_cancel, paid), None);
+    }
+
+    #[test]
+    fn accepts_signatures_of_the_stub() {
+        let body = br#"{"id": "evt_1", "type": "payment.succeeded", "user_id": 1}"#;
+        let header = signature_header("secret", NOW, body).unwrap();
+        assert!(verify_signature("secret", &header, body, NOW).is_ok());
+        assert!(verify_signature("secret", &header, body, NOW + 60).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let body = br#"{"id": "evt_1", "type": "payment.succeeded", "user_id": 1}"#;
+        let header = signature_header("secret", NOW, body).unwrap();
+        assert!(verify_signature("other", &header, body, NOW).is_err());
+        assert!(verify_signature("secret", &header, b"{}", NOW).is_err());
+        // Replayed later or from the future
+        assert!(verify_signature("secret", &header, body, NOW + 301).is_err());
+        assert!(verify_signature("secret", &header, body, NOW - 301).is_err());
+
+        let signature = header.split_once(",v1=").unwrap().1;
+        let moved = format!("t={},v1={}", NOW + 1, signature);
+        assert!(verify_signature("secret", &moved, body, NOW).is_err());
+        for malformed in ["", "t=1", &format!("v1={}", signature), "t=x,v1=zz"] {
+            assert!(verify_signature("secret", malformed, body, NOW).is_err());
+        }
+    }
+
+    // The database is never reached, these requests are turned away before
+    async fn post_event(signature: Option<String>, body: &'static str) -> StatusCode {
+        config::init_for_tests();
+        let pool: Pool = Pool::builder()
+            .build_unchecked(ConnectionManager::new(config::get().database_url.clone()));
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .route("/webhooks/payment", web::post().to(payment_webhook)),
+        )
+        .await;
+        let mut req = test::TestRequest::post()
+            .uri("/webhooks/payment")
+            .set_payload(body);
+        if let Some(signature) = signature {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+        test::call_service(&mut app, req.to_request())
+            .await
+            .status()
+    }
+
+    #[actix_rt::test]
+    async fn webhook_rejects_unsigned_and_forged_events() {
+        let body = r#"{"id": "evt_1", "type": "payment.succeeded", "user_id": 1}"#;
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(post_event(None, body).await, StatusCode::UNAUTHORIZED);
+
+        let forged = signature_header("guessed", now, body.as_bytes()).unwrap();
+        assert_eq!(
+            post_event(Some(forged), body).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let stale = signature_header(TEST_WEBHOOK_SECRET, now - 600, body.as_bytes()).unwrap();
+        assert_eq!(
+            post_event(Some(stale), body).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn webhook_checks_signed_events() {
+        let body = "not json";
+        let now = chrono::Utc::now().timestamp();
+        let signed = signature_header(TEST_WEBHOOK_SECRET, now, body.as_bytes()).unwrap();
+        assert_eq!(
+            post_event(Some(signed), body).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+// Accept codes from one step before and after the current one to allow for clock drift
+const ALLOWED_DRIFT: i64 = 1;
+const SECRET_ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> String {
+    let secret: [u8; 20] = rand::thread_rng().gen();
+    base32::encode(SECRET_ALPHABET, &secret)
+}
+
+// otpauth:// uri authenticator apps read from a QR code
+pub fn provisioning_uri(secret: &str, account: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        url_encode(issuer),
+        url_encode(account),
+        secret,
+        url_encode(issuer),
+        DIGITS,
+        PERIOD
+    )
+}
+
+// Returns the time step the code belongs to, so callers can reject a code that was already used
+pub fn verify_code(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let key = base32::decode(SECRET_ALPHABET, secret)?;
+    let code = code.trim();
+    // Checked before parsing, which would take "+01234" for 1234
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let current_step = unix_time / PERIOD;
+    (current_step - ALLOWED_DRIFT..=current_step + ALLOWED_DRIFT)
+        .find(|step| hotp(&key, *step as u64) == Some(code))
+}
+
+// HOTP as described in RFC 4226
+fn hotp(key: &[u8], counter: u64) -> Option<u32> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).ok()?;
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    Some(binary % 10u32.pow(DIGITS))
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The key of the RFC 4226 and RFC 6238 test vectors, "12345678901234567890" in base32
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc_4226_vectors() {
+        let key = base32::decode(SECRET_ALPHABET, SECRET).unwrap();
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(&key, counter as u64), Some(*code));
+        }
+    }
+
+    #[test]
+    fn verifies_rfc_6238_vectors() {
+        // The last six digits of the SHA1 vectors
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(verify_code(SECRET, code, time), Some(time / PERIOD));
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        // 287082 belongs to step 1, seconds 30 to 59
+        assert_eq!(verify_code(SECRET, "287082", 0), Some(1));
+        assert_eq!(verify_code(SECRET, "287082", 89), Some(1));
+        assert_eq!(verify_code(SECRET, "287082", 90), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes_and_secrets() {
+        assert_eq!(verify_code(SECRET, " 287082\n", 59), Some(1));
+        assert_eq!(verify_code(SECRET, "28708", 59), None);
+        assert_eq!(verify_code(SECRET, "0287082", 59), None);
+        assert_eq!(verify_code(SECRET, "005924", 1234567890), Some(41152263));
+        assert_eq!(verify_code(SECRET, "+05924", 1234567890), None);
+        assert_eq!(verify_code(SECRET, "abcdef", 59), None);
+        assert_eq!(verify_code("not base32!", "287082", 59), None);
+    }
+
+    #[test]
+    fn generates_usable_secrets() {
+        let secret = generate_secret();
+        assert_eq!(base32::decode(SECRET_ALPHABET, &secret).unwrap().len(), 20);
+        assert_ne!(secret, generate_secret());
+    }
+
+    #[test]
+    fn encodes_the_provisioning_uri() {
+        assert_eq!(
+            provisioning_uri("ABC", "alice@example.com", "Trove Sync"),
+            "otpauth://totp/Trove%20Sync:alice%40example.com?secret=ABC&issuer=Trove%20Sync&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -1,40 +1,164 @@
 use crate::{errors::ServiceError, vars};
+use actix_web::http::HeaderMap;
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use base64::{decode, encode};
+use argon2::{Algorithm, Argon2, Params, Version};
 use pbkdf2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Pbkdf2,
+    Algorithm as Pbkdf2Algorithm, Pbkdf2,
 };
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
 use rand::Rng;
+use sha2::{Digest, Sha256};
 
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                         abcdefghijklmnopqrstuvwxyz\
                         0123456789";
 const API_TOKEN_LEN: usize = 30;
+const REFRESH_TOKEN_LEN: usize = 48;
+const RECOVERY_CODE_LEN: usize = 10;
+const MAX_PASSWORD_LEN: usize = 128;
+const MAX_EMAIL_LEN: usize = 254;
 
 pub fn hash_password(password: &str) -> Result<String, ServiceError> {
     let salt = SaltString::generate(&mut OsRng);
-    // Hash password to PHC string ($pbkdf2-sha256$...)
-    let password_hash = Pbkdf2
-        .hash_password(password.as_bytes(), &salt)
-        .unwrap()
-        .to_string();
-
-    // Verify password against PHC string
-    let parsed_hash = PasswordHash::new(&password_hash).unwrap();
-    Ok(parsed_hash.to_string())
+    // Hash password to PHC string ($argon2id$... or $pbkdf2-sha256$...)
+    let password_hash = match vars::password_hash_algorithm().as_str() {
+        "pbkdf2" => Pbkdf2.hash_password(password.as_bytes(), &salt),
+        _ => argon2_hasher()?.hash_password(password.as_bytes(), &salt),
+    }
+    .map_err(|_| ServiceError::InternalServerError)?;
+    Ok(password_hash.to_string())
 }
 
 pub fn verify(hash: &str, password: &str) -> Result<bool, ServiceError> {
-    let parsed_hash = PasswordHash::new(hash).unwrap();
-    return Ok(Pbkdf2
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok());
+    let parsed_hash = PasswordHash::new(hash).map_err(|_| ServiceError::InternalServerError)?;
+    // The parameters of the stored hash are used for verification, not the configured ones
+    let verifiers: [&dyn PasswordVerifier; 2] = [&Argon2::default(), &Pbkdf2];
+    Ok(parsed_hash.verify_password(&verifiers, password).is_ok())
+}
+
+// Hash of a random password, made once with the configured parameters. Checking a password of an
+// account that doesn't exist against it takes as long as for one that does.
+pub fn dummy_hash() -> Result<&'static str, ServiceError> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash);
+    }
+    let hash = hash_password(&generate_token(REFRESH_TOKEN_LEN))?;
+    Ok(DUMMY_HASH.get_or_init(|| hash))
+}
+
+// Whether a stored hash was made with another algorithm or other parameters than configured now
+pub fn needs_rehash(hash: &str) -> bool {
+    let parsed_hash = match PasswordHash::new(hash) {
+        Ok(h) => h,
+        Err(_) => return true,
+    };
+    if vars::password_hash_algorithm() == "pbkdf2" {
+        return parsed_hash.algorithm != Pbkdf2Algorithm::Pbkdf2Sha256.ident();
+    }
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    let (m_cost, t_cost, p_cost) = vars::argon2_params();
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => {
+            params.m_cost() != m_cost || params.t_cost() != t_cost || params.p_cost() != p_cost
+        }
+        Err(_) => true,
+    }
+}
+
+fn argon2_hasher() -> Result<Argon2<'static>, ServiceError> {
+    let (m_cost, t_cost, p_cost) = vars::argon2_params();
+    let params =
+        Params::new(m_cost, t_cost, p_cost, None).map_err(|_| ServiceError::InternalServerError)?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+// Trims and lowercases an email, returns why it is not a plausible address if it isn't.
+// Emails are stored normalized, so they can be compared as they are.
+pub fn normalize_email(raw: &str) -> Result<String, String> {
+    let normalized = raw.trim().to_lowercase();
+    if normalized.len() > MAX_EMAIL_LEN {
+        return Err(format!("Email must be at most {} characters long", MAX_EMAIL_LEN));
+    }
+    let mut parts = normalized.splitn(2, '@');
+    let local_part = parts.next().unwrap_or_default();
+    let domain = parts.next().unwrap_or_default();
+    let valid = !local_part.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !normalized.chars().any(|c| c.is_whitespace() || c.is_control());
+    if valid {
+        Ok(normalized)
+    } else {
+        Err(String::from("Email is not a valid address"))
+    }
+}
+
+// Whether the name is in the IANA timezone database, like `Europe/Berlin` or `UTC`
+pub fn is_valid_timezone(name: &str) -> bool {
+    name.parse::<chrono_tz::Tz>().is_ok()
+}
+
+// Returns why a password is too weak, if it is
+pub fn check_password_policy(password: &str, user_email: &str) -> Result<(), String> {
+    password_policy(password, user_email, vars::password_min_length())
+}
+
+fn password_policy(password: &str, user_email: &str, min_length: usize) -> Result<(), String> {
+    let length = password.chars().count();
+    if length < min_length {
+        return Err(format!("Password must be at least {} characters long", min_length));
+    }
+    // Hashing is deliberately slow, so don't let anyone feed it huge inputs
+    if length > MAX_PASSWORD_LEN {
+        return Err(format!("Password must be at most {} characters long", MAX_PASSWORD_LEN));
+    }
+    let local_part = user_email.split('@').next().unwrap_or_default().to_lowercase();
+    if local_part.len() >= 3 && password.to_lowercase().contains(&local_part) {
+        return Err(String::from("Password must not contain your email address"));
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < 2 {
+        return Err(String::from(
+            "Password must mix at least two of lowercase, uppercase, digits and symbols",
+        ));
+    }
+    Ok(())
 }
 
 pub fn generate_api_token() -> String {
+    generate_token(API_TOKEN_LEN)
+}
+
+pub fn generate_refresh_token() -> String {
+    generate_token(REFRESH_TOKEN_LEN)
+}
+
+pub fn generate_recovery_code() -> String {
+    generate_token(RECOVERY_CODE_LEN)
+}
+
+pub fn generate_request_id() -> String {
+    let id: [u8; 16] = rand::thread_rng().gen();
+    hex::encode(id)
+}
+
+fn generate_token(len: usize) -> String {
     let mut rng = rand::thread_rng();
-    (0..API_TOKEN_LEN)
+    (0..len)
         .map(|_| {
             let idx = rng.gen_range(0..CHARSET.len());
             CHARSET[idx] as char
@@ -42,23 +166,155 @@ pub fn generate_api_token() -> String {
         .collect()
 }
 
-pub fn decode_token(credentials: BearerAuth) -> String {
-    let decoded_token_buffer: Vec<u8> = decode(credentials.token()).unwrap();
-    let s = match std::str::from_utf8(&decoded_token_buffer) {
-        Ok(v) => v,
-        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-    };
-    s.to_string()
+// Refresh tokens are only stored as their SHA-256 digest
+pub fn hash_token(raw_token: &str) -> String {
+    hex::encode(Sha256::digest(raw_token.as_bytes()))
+}
+
+pub fn decode_token(credentials: BearerAuth) -> Result<String, ServiceError> {
+    let malformed = || ServiceError::AuthenticationError(String::from("Malformed token"));
+    let decoded_token_buffer: Vec<u8> = decode(credentials.token()).map_err(|_| malformed())?;
+    String::from_utf8(decoded_token_buffer).map_err(|_| malformed())
+}
+
+// Address of the client without the port. `X-Forwarded-For` is only believed for requests from
+// one of the TRUSTED_PROXIES, anybody else could send it to pass for another client.
+pub fn client_ip(peer: Option<SocketAddr>, headers: &HeaderMap) -> String {
+    let forwarded_for: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    resolve_client_ip(
+        peer.map(|addr| addr.ip()),
+        &forwarded_for.join(","),
+        &vars::trusted_proxies(),
+    )
+    .map(|ip| ip.to_string())
+    .unwrap_or_else(|| String::from("unknown"))
+}
+
+// Each proxy appends the address it got the request from, so the client is the last address
+// that wasn't added by a trusted proxy
+fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: &str,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut ip = peer?;
+    for hop in forwarded_for.rsplit(',') {
+        if !trusted.contains(&ip) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(forwarded) => ip = forwarded,
+            Err(_) => break,
+        }
+    }
+    Some(ip)
 }
 
 pub fn encode_text(txt: String) -> String {
     encode(&txt)
 }
-pub fn decode_text(txt: String) -> String {
-    let decoded_buffer: Vec<u8> = decode(txt).unwrap();
-    let s = match std::str::from_utf8(&decoded_buffer) {
-        Ok(v) => v,
-        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-    };
-    s.to_string()
-}
\ No newline at end of file
+// Stored troves are always encoded by us, failing to decode one means the data is corrupt
+pub fn decode_text(txt: String) -> Result<String, ServiceError> {
+    let decoded_buffer: Vec<u8> = decode(txt).map_err(|_| ServiceError::InternalServerError)?;
+    String::from_utf8(decoded_buffer).map_err(|_| ServiceError::InternalServerError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_emails() {
+        assert_eq!(
+            normalize_email("  Alice@Example.COM "),
+            Ok(String::from("alice@example.com"))
+        );
+        assert_eq!(
+            normalize_email("a.b+trove@sub.example.com"),
+            Ok(String::from("a.b+trove@sub.example.com"))
+        );
+        for invalid in [
+            "",
+            "alice",
+            "@example.com",
+            "alice@",
+            "alice@example",
+            "alice@.example.com",
+            "alice@example.com.",
+            "alice@exa@mple.com",
+            "al ice@example.com",
+            "alice@exam\tple.com",
+        ] {
+            assert!(normalize_email(invalid).is_err(), "{:?}", invalid);
+        }
+        let long = format!("{}@example.com", "a".repeat(MAX_EMAIL_LEN));
+        assert!(normalize_email(&long).is_err());
+    }
+
+    #[test]
+    fn timezones_from_the_tz_database() {
+        for valid in ["UTC", "Europe/Berlin", "America/Argentina/Buenos_Aires", "Etc/GMT+5"] {
+            assert!(is_valid_timezone(valid), "{}", valid);
+        }
+        for invalid in ["", "Mars/Olympus_Mons", "Europe/Berlin/", "europe/berlin", "Europe"] {
+            assert!(!is_valid_timezone(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn password_policy_rules() {
+        assert!(password_policy("correct-horse-42", "alice@example.com", 10).is_ok());
+        assert!(password_policy("Sh0rt!", "alice@example.com", 10).is_err());
+        assert!(password_policy(&"aA1!".repeat(40), "alice@example.com", 10).is_err());
+        assert!(password_policy("my-Alice-password", "alice@example.com", 10).is_err());
+        // Local parts that short would rule out too many passwords
+        assert!(password_policy("password-of-al", "al@example.com", 10).is_ok());
+        assert!(password_policy("onlylowercaseletters", "alice@example.com", 10).is_err());
+        assert!(password_policy("ÄÖÜäöüßéèêë", "alice@example.com", 10).is_ok());
+    }
+
+    #[test]
+    fn dummy_hash_matches_no_password() {
+        crate::config::init_for_tests();
+        let hash = dummy_hash().unwrap();
+        assert_eq!(dummy_hash().unwrap(), hash);
+        assert!(!needs_rehash(hash));
+        assert!(!verify(hash, "").unwrap());
+        assert!(!verify(hash, "correct-horse-42").unwrap());
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_needs_a_trusted_peer() {
+        let proxy = ip("10.0.0.2");
+        let client = resolve_client_ip(Some(ip("203.0.113.7")), "198.51.100.1", &[proxy]);
+        assert_eq!(client, Some(ip("203.0.113.7")));
+
+        let client = resolve_client_ip(Some(proxy), "198.51.100.1", &[]);
+        assert_eq!(client, Some(proxy));
+    }
+
+    #[test]
+    fn forwarded_for_from_trusted_proxies() {
+        let proxies = [ip("10.0.0.2"), ip("10.0.0.3")];
+        // Whatever the client put in front of the chain is ignored
+        let client = resolve_client_ip(
+            Some(ip("10.0.0.2")),
+            "1.2.3.4, 198.51.100.1, 10.0.0.3",
+            &proxies,
+        );
+        assert_eq!(client, Some(ip("198.51.100.1")));
+
+        let client = resolve_client_ip(Some(ip("10.0.0.2")), "", &proxies);
+        assert_eq!(client, Some(ip("10.0.0.2")));
+        let client = resolve_client_ip(Some(ip("10.0.0.2")), "garbage", &proxies);
+        assert_eq!(client, Some(ip("10.0.0.2")));
+        assert_eq!(resolve_client_ip(None, "1.2.3.4", &proxies), None);
+    }
+}
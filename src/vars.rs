@@ -1,28 +1,207 @@
-use dotenv::dotenv;
-use std::env::var;
+// Accessors for the settings in `config::Config`, which documents them
+use crate::config::{self, Config};
+use crate::quota::Limits;
+use std::net::IpAddr;
+
+fn config() -> &'static Config {
+    config::get()
+}
 
 pub fn database_url() -> String {
-    dotenv().ok();
-    var("DATABASE_URL").expect("DATABASE_URL must be set")
+    config().database_url.clone()
 }
 
 pub fn secret_key() -> String {
-    dotenv().ok();
-    var("SECRET_KEY").unwrap_or_else(|_| "0123".repeat(8))
+    config().secret_key.clone()
 }
 
+// Not the stored `verified` flag, see `Config::verify_email`
 pub fn verify_email() -> bool {
-    dotenv().ok();
-    let env_var = var("VERIFY_USER").unwrap_or_else(|_| "true".to_string());
-    env_var.parse::<bool>().unwrap()
+    config().verify_email
+}
+
+pub fn port() -> u16 {
+    config().port
+}
+
+pub fn bind_address() -> String {
+    config().bind_address.clone()
+}
+
+pub fn keep_alive() -> usize {
+    config().keep_alive
+}
+
+pub fn workers() -> Option<usize> {
+    config().workers
+}
+
+pub fn jwt_issuer() -> String {
+    config().jwt_issuer.clone()
+}
+
+pub fn access_token_ttl() -> i64 {
+    config().access_token_ttl
+}
+
+pub fn refresh_token_ttl() -> i64 {
+    config().refresh_token_ttl
+}
+
+pub fn oidc_issuer() -> Option<String> {
+    config().oidc_issuer.clone()
+}
+
+pub fn oidc_audience() -> Option<String> {
+    config().oidc_audience.clone()
+}
+
+pub fn oidc_jwks_url() -> Option<String> {
+    config().oidc_jwks_url.clone()
+}
+
+pub fn oidc_jwks_cache_ttl() -> u64 {
+    config().oidc_jwks_cache_ttl
+}
+
+pub fn oidc_jwks_refetch_interval() -> u64 {
+    config().oidc_jwks_refetch_interval
+}
+
+pub fn public_url() -> String {
+    config().public_url()
+}
+
+pub fn verification_token_ttl() -> i64 {
+    config().verification_token_ttl
+}
+
+pub fn password_reset_token_ttl() -> i64 {
+    config().password_reset_token_ttl
+}
+
+pub fn mailer() -> String {
+    config().mailer.clone()
+}
+
+pub fn mail_file() -> String {
+    config().mail_file.clone()
+}
+
+pub fn mail_from() -> String {
+    config().mail_from.clone()
+}
+
+// Checked to be set when the smtp mailer is configured
+pub fn smtp_host() -> String {
+    config().smtp_host.clone().unwrap_or_default()
+}
+
+pub fn smtp_username() -> String {
+    config().smtp_username.clone()
+}
+
+pub fn smtp_password() -> String {
+    config().smtp_password.clone()
+}
+
+pub fn totp_issuer() -> String {
+    config().totp_issuer.clone()
+}
+
+pub fn rate_limit_store() -> String {
+    config().rate_limit_store.clone()
+}
+
+// Burst size and requests per minute
+pub fn trusted_proxies() -> Vec<IpAddr> {
+    config().trusted_proxies.clone()
+}
+
+pub fn ip_rate_limit() -> (f64, f64) {
+    (
+        config().ip_rate_limit_burst,
+        config().ip_rate_limit_per_minute,
+    )
+}
+
+// Burst size and requests per minute
+pub fn email_rate_limit() -> (f64, f64) {
+    (
+        config().email_rate_limit_burst,
+        config().email_rate_limit_per_minute,
+    )
+}
+
+pub fn lockout_max_failures() -> i32 {
+    config().lockout_max_failures
+}
+
+pub fn lockout_duration() -> i64 {
+    config().lockout_duration
+}
+
+pub fn free_limits() -> Limits {
+    config().free_limits
+}
+
+pub fn subscribed_limits() -> Limits {
+    config().subscribed_limits
+}
+
+pub fn password_hash_algorithm() -> String {
+    config().password_hash_algorithm.clone()
+}
+
+// Memory cost in KiB, iterations and parallelism for Argon2id
+pub fn argon2_params() -> (u32, u32, u32) {
+    let config = config();
+    (
+        config.argon2_memory_kib,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+    )
+}
+
+pub fn password_min_length() -> usize {
+    config().password_min_length
+}
+
+pub fn account_deletion_grace_period() -> i64 {
+    config().account_deletion_grace_period
+}
+
+pub fn account_purge_interval() -> u64 {
+    config().account_purge_interval
+}
+
+pub fn auto_migrate() -> bool {
+    config().auto_migrate
+}
+
+pub fn payment_webhook_secret() -> Option<String> {
+    config()
+        .payment_webhook_secret
+        .clone()
+        .filter(|s| !s.is_empty())
+}
+
+pub fn subscription_grace_period() -> i64 {
+    config().subscription_grace_period
+}
+
+pub fn metrics_token() -> Option<String> {
+    config().metrics_token.clone().filter(|s| !s.is_empty())
+}
+
+pub fn run_jobs() -> bool {
+    config().run_jobs
 }
 
-pub fn port() -> String {
-    dotenv().ok();
-    var("PORT").unwrap_or_else(|_| "8080".to_string())
+pub fn job_poll_interval() -> u64 {
+    config().job_poll_interval
 }
 
-pub fn uri() -> String {
-    dotenv().ok();
-    var("ACTIX_URI").unwrap_or_else(|_| "0.0.0.0".to_string())
+pub fn shutdown_timeout() -> u64 {
+    config().shutdown_timeout
 }